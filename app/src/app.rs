@@ -41,12 +41,15 @@ impl AppState {
             .ok()
             .and_then(|s| toml::from_str::<AnalysisConfig>(&s).ok())
             .unwrap_or_default();
+        if let Err(e) = cfg.validate() {
+            log::warn!("{e}");
+        }
 
         let spectrogram =
             spectrogram::Spectrogram::new(&cc.egui_ctx, &cfg, sample_rx, audio_tx);
         let playback =
             audio::Playback::new(&mut persistent.audio, sample_tx, audio_rx, &cfg);
-        let ease = easing::EaseEditor::new(&spectrogram.state.easing);
+        let ease = easing::EaseEditor::new(&spectrogram.analyzer.state().easing);
         let light = light::Light::new(&cc.egui_ctx, &cfg.light);
         let serial_thread = Some(SerialPortThread::new());
 
@@ -65,7 +68,7 @@ impl AppState {
 
 impl eframe::App for AppState {
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
-        self.persistent.easing = self.spectrogram.state.easing.clone();
+        self.persistent.easing = self.spectrogram.analyzer.state().easing.clone();
         eframe::set_value::<PersistentAppState>(storage, eframe::APP_KEY, &self.persistent);
     }
 
@@ -83,8 +86,12 @@ impl eframe::App for AppState {
 
         egui::SidePanel::left("Configuration").show(ctx, |ui| {
             ui.with_layout(Layout::bottom_up(egui::Align::Min), |ui| {
-                self.light
-                    .ui(ctx, ui, &self.cfg.light, &self.spectrogram.state.paint);
+                self.light.ui(
+                    ctx,
+                    ui,
+                    &self.cfg.light,
+                    &self.spectrogram.analyzer.state().paint,
+                );
                 ui.with_layout(Layout::default(), |ui| {
                     ScrollArea::vertical().show(ui, |ui| {
                         CollapsingHeader::new("Audio").show(ui, |ui| {
@@ -94,7 +101,11 @@ impl eframe::App for AppState {
                                 &mut self.playback,
                                 &mut self.cfg.loudness,
                             );
-                            audio::playback(&self.cfg, &mut self.persistent.audio, &mut self.playback);
+                            audio::playback(
+                                &self.cfg,
+                                &mut self.persistent.audio,
+                                &mut self.playback,
+                            );
                         });
                         ui.separator();
                         CollapsingHeader::new("Spectrogram").show(ui, |ui| {
@@ -108,7 +119,12 @@ impl eframe::App for AppState {
                         });
                         ui.separator();
                         CollapsingHeader::new("Easing").show(ui, |ui| {
-                            self.ease.ui(ui, &mut self.spectrogram.state.easing);
+                            let easing = &mut self.spectrogram.analyzer.state_mut().easing;
+                            self.ease.ui(ui, easing);
+                            if ui.button("Freeze and export easing").clicked() {
+                                easing.freeze();
+                                fs::write("easing.toml", toml::to_string(easing).unwrap()).unwrap();
+                            }
                         });
 
                         let export = ui.button("Export config");
@@ -116,7 +132,7 @@ impl eframe::App for AppState {
                             fs::write("config.toml", toml::to_string(&self.cfg).unwrap()).unwrap();
                             fs::write(
                                 "easing.toml",
-                                toml::to_string(&self.spectrogram.state.easing).unwrap(),
+                                toml::to_string(&self.spectrogram.analyzer.state().easing).unwrap(),
                             )
                             .unwrap();
                         }
@@ -127,7 +143,7 @@ impl eframe::App for AppState {
 
         self.spectrogram
             .hps_energy
-            .ui(&mut self.persistent.spec_cfg.power, ctx);
+            .ui(&mut self.persistent.spec_cfg.power, &self.cfg, ctx);
 
         let panel = egui::CentralPanel::default().frame(Frame::none().inner_margin(0.0));
         panel.show(ctx, |ui| {
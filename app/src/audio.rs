@@ -2,6 +2,7 @@ use std::{
     collections::VecDeque,
     fs::{self, File},
     io::{self, BufReader},
+    iter,
     path::{Path, PathBuf},
     sync::{
         OnceLock,
@@ -25,6 +26,13 @@ use rodio::{
 
 type AudioDecoder = TrackPosition<Decoder<BufReader<File>>>;
 
+/// A hop worth of interleaved samples read from the decoder
+pub struct Hop {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub samples: Vec<i16>,
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Default)]
 #[serde(default)]
 pub struct Audio {
@@ -38,6 +46,7 @@ pub struct Audio {
     pub harmonic: bool,
     pub percussive: bool,
     pub residual: bool,
+    pub vocal: bool,
 }
 
 impl Audio {
@@ -168,7 +177,8 @@ pub fn ui(ui: &mut Ui, audio: &mut Audio, playback: &mut Playback, cfg: &mut Lou
             audio.hps,
             Checkbox::new(&mut audio.percussive, "Percussive"),
         );
-        if [hps, h, r, p].iter().any(|r| r.changed()) {
+        let v = ui.add_enabled(audio.hps, Checkbox::new(&mut audio.vocal, "Vocal"));
+        if [hps, h, r, p, v].iter().any(|r| r.changed()) {
             playback
                 .decoder
                 .as_mut()
@@ -179,6 +189,7 @@ pub fn ui(ui: &mut Ui, audio: &mut Audio, playback: &mut Playback, cfg: &mut Lou
     });
 
     ui.checkbox(&mut cfg.normalize, "Normalize");
+    ui.checkbox(&mut cfg.silence.enabled, "Idle on silence");
 }
 
 pub struct Playback {
@@ -186,7 +197,7 @@ pub struct Playback {
     pub dummy_sink: Sink,
     audio_sink: Sink,
     _stream: OutputStream, // DONT DROP
-    sample_tx: Sender<Vec<i16>>,
+    sample_tx: Sender<Hop>,
     audio_rx: Receiver<Vec<i16>>,
     istft: fft::InverseStft,
     playing_for: u32,
@@ -197,7 +208,7 @@ pub struct Playback {
 impl Playback {
     pub fn new(
         audio: &mut Audio,
-        sample_tx: Sender<Vec<i16>>,
+        sample_tx: Sender<Hop>,
         audio_rx: Receiver<Vec<i16>>,
         cfg: &AnalysisConfig,
     ) -> Self {
@@ -228,35 +239,52 @@ impl Playback {
     }
 
     /// Call this when samples are received by `spectrogram` to modify the
-    /// samples to be played if necessary
+    /// samples to be played if necessary. `cfg` has to be the one `state`
+    /// was analyzed with, see `Analyzer::cfg`.
     pub fn audio_samples(
         &mut self,
         audio: &Audio,
-        samples: Vec<i16>,
+        hop: &Hop,
         cfg: &AnalysisConfig,
         state: &AnalysisState,
     ) -> Vec<i16> {
         if audio.hps {
-            let mut spec = RawSpec::<Complex<f32>>::blank_default(cfg);
-            for (i, val) in spec.audible_slice_mut(cfg).iter_mut().enumerate() {
-                *val += state.hps.harmonic[i] * audio.harmonic as u32 as f32;
-                *val += state.hps.residual[i] * audio.residual as u32 as f32;
-                *val += state.hps.percussive[i] * audio.percussive as u32 as f32;
-            }
-
-            self.istft.push(spec.0).collect()
+            let spec = audition_spectrum(audio, cfg, state);
+
+            // the analysis is mono so play it back on every channel
+            self.istft
+                .push(&spec)
+                .map(|s| (s * i16::MAX as f32) as i16)
+                .flat_map(|s| iter::repeat_n(s, hop.channels as usize))
+                .collect()
         } else {
-            samples
+            hop.samples.clone()
         }
     }
 }
 
+/// Sums the parts of the HPS selected in `audio` back into a whole spectrum
+fn audition_spectrum(
+    audio: &Audio,
+    cfg: &AnalysisConfig,
+    state: &AnalysisState,
+) -> RawSpec<Complex<f32>> {
+    let mut spec = RawSpec::<Complex<f32>>::blank_default(cfg);
+    for (i, val) in spec.audible_slice_mut(cfg).iter_mut().enumerate() {
+        *val += state.hps.harmonic[i] * audio.harmonic as u32 as f32;
+        *val += state.hps.residual[i] * audio.residual as u32 as f32;
+        *val += state.hps.percussive[i] * audio.percussive as u32 as f32;
+        *val += state.hps.vocal[i] * audio.vocal as u32 as f32;
+    }
+    spec
+}
+
 pub fn playback(cfg: &AnalysisConfig, audio: &mut Audio, playback: &mut Playback) {
     puffin::profile_function!();
     // *sigh* okay this is very jank but the vec cannot be sent between threads
     // because the callback in `EmptyCallback` has to satisfy `Fn`
-    static SAMPLE_QUEUE: OnceLock<Mutex<VecDeque<Vec<i16>>>> = OnceLock::new();
-    static SAMPLE_TX: OnceLock<Sender<Vec<i16>>> = OnceLock::new();
+    static SAMPLE_QUEUE: OnceLock<Mutex<VecDeque<Hop>>> = OnceLock::new();
+    static SAMPLE_TX: OnceLock<Sender<Hop>> = OnceLock::new();
 
     // we may clear the playback sink in audio::ui
     // not robust to other modifications but its fiiiiine
@@ -278,7 +306,6 @@ pub fn playback(cfg: &AnalysisConfig, audio: &mut Audio, playback: &mut Playback
     }
 
     if audio.playing {
-        let hop_len = cfg.fft.hop_len;
         let target_samples = 48;
 
         // if there are no more samples left to read
@@ -317,6 +344,9 @@ pub fn playback(cfg: &AnalysisConfig, audio: &mut Audio, playback: &mut Playback
             }
         }
 
+        let channels = decoder.channels();
+        let sample_rate = decoder.sample_rate();
+        let hop_len = cfg.fft.hop_len * channels as usize;
         while playback.dummy_sink.len() < target_samples * 2 {
             let samples = decoder.take(hop_len).collect::<Vec<_>>();
 
@@ -331,7 +361,11 @@ pub fn playback(cfg: &AnalysisConfig, audio: &mut Audio, playback: &mut Playback
                 SAMPLE_QUEUE
                     .get_or_init(|| Default::default())
                     .lock()
-                    .push_back(samples);
+                    .push_back(Hop {
+                        channels,
+                        sample_rate,
+                        samples,
+                    });
                 SAMPLE_TX.get_or_init(|| playback.sample_tx.clone());
 
                 playback
@@ -372,3 +406,37 @@ pub fn playback(cfg: &AnalysisConfig, audio: &mut Audio, playback: &mut Playback
         playback.audio_sink.append(buffer);
     }
 }
+
+#[test]
+fn test_audition_sample_rate() {
+    use lib::Analyzer;
+    use std::f32::consts::TAU;
+
+    // config.toml's rate, but the file is 48k
+    let cfg = AnalysisConfig::default();
+    let sample_rate = 48000;
+    let mut analyzer = Analyzer::new(cfg.clone(), 1, sample_rate);
+    let samples = (0..sample_rate as usize / 2)
+        .map(|i| (f32::sin(i as f32 / sample_rate as f32 * 440.0 * TAU) * 8000.0) as i16)
+        .collect::<Vec<_>>();
+    analyzer.push(&samples);
+    while analyzer.next_frame().is_some() {}
+
+    let audio = Audio {
+        hps: true,
+        harmonic: true,
+        percussive: true,
+        residual: true,
+        ..Default::default()
+    };
+    let cfg = analyzer.cfg();
+    let spec = audition_spectrum(&audio, cfg, analyzer.state());
+    assert_eq!(spec.len(), cfg.raw_len());
+    let (peak, _) = spec
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.norm().total_cmp(&b.1.norm()))
+        .unwrap();
+    let hz = cfg.idx_to_hz(peak);
+    assert!((hz - 440.0).abs() <= cfg.idx_to_hz(1), "{hz}");
+}
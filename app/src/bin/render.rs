@@ -0,0 +1,159 @@
+//! Renders audio files to LED frames without opening the visualizer.
+//!
+//! ```text
+//! cargo run --release --bin render -- [-o <dir>] [--lookahead] <files>...
+//! ```
+//!
+//! Every input `song.mp3` produces a `song.frames` file (next to the input, or
+//! in `<dir>` if given). All numbers are little endian:
+//!
+//! ```text
+//! header: b"LEDF" | width: u32 | height: u32
+//! frame:  time: f32 | width * height * [r: u8, g: u8, b: u8]
+//! ```
+//!
+//! `time` is the position in seconds of the end of the hop the frame was
+//! computed from, and pixels are stored row by row just like
+//! `PaintData::colors`. With `--lookahead` (or `lookahead.enabled` in the
+//! config) the analysis looks ahead to line every frame up with the audio
+//! around `time` instead of lagging behind it.
+//!
+//! The song structure found by `lib::segment` is written to
+//! `song.segments.toml` next to the frames.
+
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use lib::{Analyzer, cfg::AnalysisConfig, segment::Segmenter, state::AnalysisState};
+use rodio::{Decoder, Source};
+
+const USAGE: &str = "usage: render [-o <dir>] [--lookahead] <files>...";
+const MAGIC: &[u8; 4] = b"LEDF";
+
+fn main() -> ExitCode {
+    let mut out_dir = None;
+    let mut lookahead = false;
+    let mut inputs = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--out-dir" => match args.next() {
+                Some(dir) => out_dir = Some(PathBuf::from(dir)),
+                None => {
+                    eprintln!("{USAGE}");
+                    return ExitCode::FAILURE;
+                }
+            },
+            "-l" | "--lookahead" => lookahead = true,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            _ => inputs.push(PathBuf::from(arg)),
+        }
+    }
+    if inputs.is_empty() {
+        eprintln!("{USAGE}");
+        return ExitCode::FAILURE;
+    }
+
+    let mut cfg = fs::read_to_string("config.toml")
+        .ok()
+        .and_then(|s| toml::from_str::<AnalysisConfig>(&s).ok())
+        .unwrap_or_default();
+    cfg.lookahead.enabled |= lookahead;
+    if let Err(e) = cfg.validate() {
+        eprintln!("{e}");
+    }
+
+    let mut ok = true;
+    for input in &inputs {
+        let output = output_path(input, out_dir.as_deref());
+        match render(&cfg, input, &output) {
+            Ok(frames) => println!(
+                "{} -> {} ({frames} frames)",
+                input.display(),
+                output.display()
+            ),
+            Err(e) => {
+                eprintln!("Failed to render {}: {e}", input.display());
+                ok = false;
+            }
+        }
+    }
+
+    if ok {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn output_path(input: &Path, out_dir: Option<&Path>) -> PathBuf {
+    let path = match out_dir {
+        Some(dir) => dir.join(input.file_name().unwrap_or_default()),
+        None => input.to_path_buf(),
+    };
+    path.with_extension("frames")
+}
+
+/// Runs the whole file through the analysis pipeline and returns the number
+/// of frames written
+fn render(cfg: &AnalysisConfig, input: &Path, output: &Path) -> io::Result<usize> {
+    let file = File::open(input)?;
+    let mut decoder = Decoder::new(BufReader::new(file)).map_err(io::Error::other)?;
+
+    let mut w = BufWriter::new(File::create(output)?);
+    w.write_all(MAGIC)?;
+    w.write_all(&cfg.light.width.to_le_bytes())?;
+    w.write_all(&cfg.light.height.to_le_bytes())?;
+
+    let mut analyzer = Analyzer::new(
+        cfg.clone(),
+        decoder.channels() as usize,
+        decoder.sample_rate(),
+    );
+    let mut segmenter = Segmenter::new(analyzer.cfg());
+    let hop_duration = cfg.fft.hop_len as f32 / decoder.sample_rate() as f32;
+    let mut frames = 0;
+    loop {
+        let chunk = decoder.by_ref().take(4096).collect::<Vec<_>>();
+        if chunk.is_empty() {
+            break;
+        }
+        analyzer.push(&chunk);
+
+        while let Some(state) = analyzer.next_frame() {
+            frames += 1;
+            segmenter.push(state);
+            write_frame(&mut w, frames as f32 * hop_duration, state)?;
+        }
+    }
+    // frames held back by the lookahead
+    while let Some(state) = analyzer.finish() {
+        frames += 1;
+        segmenter.push(state);
+        write_frame(&mut w, frames as f32 * hop_duration, state)?;
+    }
+    w.flush()?;
+
+    let segments = segmenter.finish(cfg);
+    let toml = toml::to_string(&segments).map_err(io::Error::other)?;
+    fs::write(output.with_extension("segments.toml"), toml)?;
+
+    Ok(frames)
+}
+
+fn write_frame(w: &mut impl Write, time: f32, state: &AnalysisState) -> io::Result<()> {
+    w.write_all(&time.to_le_bytes())?;
+    for c in &state.paint.colors {
+        w.write_all(&[c.r(), c.g(), c.b()])?;
+    }
+    Ok(())
+}
@@ -123,13 +123,40 @@ impl EaseEditor {
                     );
                 }
             });
+        // learned values would just be overwritten next hop
+        let learning = ease.adaptive.learning();
         ui.horizontal(|ui| {
             ui.label("Min");
-            ui.add(Slider::new(&mut ease.min, 0.0..=5.0).clamping(SliderClamping::Never));
+            ui.add_enabled(
+                !learning,
+                Slider::new(&mut ease.min, 0.0..=5.0).clamping(SliderClamping::Never),
+            );
         });
         ui.horizontal(|ui| {
             ui.label("Max");
-            ui.add(Slider::new(&mut ease.max, 0.0..=5.0).clamping(SliderClamping::Never));
+            ui.add_enabled(
+                !learning,
+                Slider::new(&mut ease.max, 0.0..=5.0).clamping(SliderClamping::Never),
+            );
+        });
+
+        let adaptive = &mut ease.adaptive;
+        ui.checkbox(&mut adaptive.enabled, "Adaptive");
+        ui.add_enabled_ui(adaptive.enabled, |ui| {
+            ui.checkbox(&mut adaptive.frozen, "Freeze");
+            ui.horizontal(|ui| {
+                ui.label("Percentiles");
+                ui.add(Slider::new(&mut adaptive.low, 0.0..=1.0));
+                ui.add(Slider::new(&mut adaptive.high, 0.0..=1.0));
+            });
+            ui.horizontal(|ui| {
+                ui.label("Window");
+                ui.add(Slider::new(&mut adaptive.window, 1..=2000).suffix(" hops"));
+            });
+            ui.horizontal(|ui| {
+                ui.label("Decay");
+                ui.add(Slider::new(&mut adaptive.decay, 0.9..=1.0));
+            });
         });
     }
 }
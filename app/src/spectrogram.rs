@@ -13,8 +13,8 @@ use std::{
 use egui::{ColorImage, Context, Image, Slider, TextureHandle, Ui, mutex::Mutex};
 
 use lib::{
+    Analyzer,
     cfg::AnalysisConfig,
-    ebur128::EbuR128,
     state::{AnalysisState, AudibleSpec},
     unit,
 };
@@ -59,6 +59,8 @@ pub enum SpecData {
     Percussive,
     HarmonicFiltered,
     PercussiveFiltered,
+    Vocal,
+    Mel,
 }
 
 impl Default for SpecConfig {
@@ -117,10 +119,11 @@ impl SpecConfig {
                 ui.end_row();
 
                 if let Some(v) = res.inner {
-                    if v[3..=5].iter().any(|v| v.changed()) {
+                    if [3, 4, 5, 8].iter().any(|&i| v[i].changed()) {
                         audio.harmonic = v[3].changed();
                         audio.residual = v[4].changed();
                         audio.percussive = v[5].changed();
+                        audio.vocal = v[8].changed();
                     }
                 }
 
@@ -142,10 +145,11 @@ impl SpecConfig {
             && let Some(decoder) = &mut playback.decoder
         {
             spec.spec.reset();
-            let mut state = AnalysisState::blank(cfg);
+            let channels = decoder.channels() as usize;
+            let mut analyzer = live_analyzer(cfg, channels, decoder.sample_rate());
             let time = decoder.get_pos();
             let hop_duration =
-                Duration::from_secs_f32(cfg.fft.hop_len as f32 / cfg.fft.sample_rate as f32);
+                Duration::from_secs_f32(cfg.fft.hop_len as f32 / decoder.sample_rate() as f32);
 
             decoder
                 .try_seek(
@@ -159,9 +163,11 @@ impl SpecConfig {
                     .checked_sub(Duration::from_secs_f32(0.001))
                     .unwrap_or_default()
             {
-                let hop = decoder.take(cfg.fft.hop_len).collect::<Vec<_>>();
-                state = AnalysisState::from_prev(cfg, state, hop.into_iter(), &mut spec.ebur);
-                spec.spec.update_from_db(&specdata(self.data, &state), self);
+                let hop = decoder.take(cfg.fft.hop_len * channels).collect::<Vec<_>>();
+                analyzer.push(&hop);
+                while let Some(state) = analyzer.next_frame() {
+                    spec.spec.update_from_db(&specdata(self.data, state), self);
+                }
             }
             decoder.try_seek(time).unwrap();
         }
@@ -201,7 +207,7 @@ impl SpectrogramImage {
         }
     }
 
-    fn update_from_db(&mut self, spec: &AudibleSpec<unit::Db>, cfg: &SpecConfig) {
+    fn update_from_db(&mut self, spec: &[unit::Db], cfg: &SpecConfig) {
         let [_, h] = self.img.size();
         let scale = self.scale;
         self.img.shift_img(|i| {
@@ -262,7 +268,7 @@ impl SpectrogramImageSet {
         }
     }
 
-    fn update_from_db(&mut self, db: &AudibleSpec<unit::Db>, cfg: &SpecConfig) {
+    fn update_from_db(&mut self, db: &[unit::Db], cfg: &SpecConfig) {
         self.linear.update_from_db(db, cfg);
         self.log.update_from_db(db, cfg);
     }
@@ -282,18 +288,17 @@ impl SpectrogramImageSet {
 
 pub struct Spectrogram {
     spec: SpectrogramImageSet,
-    sample_rx: Receiver<Vec<i16>>,
+    sample_rx: Receiver<audio::Hop>,
     audio_tx: Sender<Vec<i16>>,
-    pub state: AnalysisState,
+    pub analyzer: Analyzer,
     pub hps_energy: graph::Graph,
-    pub ebur: EbuR128,
 }
 
 impl Spectrogram {
     pub fn new(
         ctx: &Context,
         cfg: &AnalysisConfig,
-        sample_rx: Receiver<Vec<i16>>,
+        sample_rx: Receiver<audio::Hop>,
         audio_tx: Sender<Vec<i16>>,
     ) -> Self {
         // let img = ColorImage::new([IMG_WIDTH, IDX_MAX], Color32::BLACK);
@@ -306,47 +311,64 @@ impl Spectrogram {
             spec: SpectrogramImageSet::new(ctx, "spectrogram"),
             sample_rx,
             audio_tx,
-            state: AnalysisState::blank(cfg),
+            analyzer: live_analyzer(cfg, 1, cfg.fft.sample_rate as u32),
             hps_energy: graph::Graph::new(512),
-            ebur: cfg.ebur(),
         }
     }
+
+    /// Starts a new analysis if the format of the incoming audio changed,
+    /// keeping any edits made to the easing functions
+    fn match_format(&mut self, cfg: &AnalysisConfig, hop: &audio::Hop) {
+        if hop.channels as usize == self.analyzer.channels()
+            && hop.sample_rate == self.analyzer.sample_rate()
+        {
+            return;
+        }
+        let easing = self.analyzer.state().easing.clone();
+        self.analyzer = live_analyzer(cfg, hop.channels as usize, hop.sample_rate);
+        self.analyzer.state_mut().easing = easing;
+    }
+}
+
+/// The app shows and plays the analysis as the audio comes in, so it can't
+/// wait for `LookaheadConfig::enabled` even when config.toml turns it on for
+/// the renderer
+fn live_analyzer(cfg: &AnalysisConfig, channels: usize, sample_rate: u32) -> Analyzer {
+    let mut cfg = cfg.clone();
+    cfg.lookahead.enabled = false;
+    Analyzer::new(cfg, channels, sample_rate)
 }
 
 pub fn ui(ui: &mut Ui, state: &mut AppState) {
     puffin::profile_function!();
     let spec = &mut state.spectrogram;
+    spec.analyzer.set_cfg(&state.cfg);
 
     let mut max_iter = 4;
-    while let Ok(samples) = spec.sample_rx.try_recv() {
+    while let Ok(hop) = spec.sample_rx.try_recv() {
         max_iter -= 1;
         if max_iter < 0 {
             break;
         }
 
-        let hop_len = state.cfg.fft.hop_len;
-        assert_eq!(samples.len(), hop_len);
-
+        spec.match_format(&state.cfg, &hop);
         spec.audio_tx
             .send(state.playback.audio_samples(
                 &state.persistent.audio,
-                samples.clone(),
-                &state.cfg,
-                &spec.state,
+                &hop,
+                spec.analyzer.cfg(),
+                spec.analyzer.state(),
             ))
             .unwrap();
 
-        take_mut::take_or_recover(
-            &mut spec.state,
-            || AnalysisState::blank(&state.cfg),
-            |s| AnalysisState::from_prev(&state.cfg, s, samples.iter().cloned(), &mut spec.ebur),
-        );
-
-        spec.spec.update_from_db(
-            &specdata(state.persistent.spec_cfg.data, &spec.state),
-            &state.persistent.spec_cfg,
-        );
-        spec.hps_energy.update(&spec.state);
+        spec.analyzer.push(&hop.samples);
+        while let Some(s) = spec.analyzer.next_frame() {
+            spec.spec.update_from_db(
+                &specdata(state.persistent.spec_cfg.data, s),
+                &state.persistent.spec_cfg,
+            );
+            spec.hps_energy.update(s);
+        }
     }
 
     // Update the shared LED image for the serial port thread
@@ -368,12 +390,12 @@ pub fn ui(ui: &mut Ui, state: &mut AppState) {
     );
 }
 
-fn specdata<'a>(data: SpecData, state: &'a AnalysisState) -> Cow<'a, AudibleSpec<unit::Db>> {
-    fn o<'a>(x: AudibleSpec<unit::Db>) -> Cow<'a, AudibleSpec<unit::Db>> {
-        Cow::Owned(x)
+fn specdata<'a>(data: SpecData, state: &'a AnalysisState) -> Cow<'a, [unit::Db]> {
+    fn o<'a>(x: AudibleSpec<unit::Db>) -> Cow<'a, [unit::Db]> {
+        Cow::Owned(x.into_inner())
     }
     match data {
-        SpecData::Normal => Cow::Borrowed(&state.fft.db),
+        SpecData::Normal => Cow::Borrowed(&state.fft.db[..]),
         SpecData::HarmonicallyEnhanced => o(state.hps.h_enhanced.into_db()),
         SpecData::PercussivelyEnhanced => o(state.hps.p_enhanced.into_db()),
         SpecData::Harmonic => o(state.hps.harmonic.into_db()),
@@ -381,5 +403,7 @@ fn specdata<'a>(data: SpecData, state: &'a AnalysisState) -> Cow<'a, AudibleSpec
         SpecData::Percussive => o(state.hps.percussive.into_db()),
         SpecData::HarmonicFiltered => o(state.hps.h_filtered.into_db()),
         SpecData::PercussiveFiltered => o(state.hps.p_filtered.into_db()),
+        SpecData::Vocal => o(state.hps.vocal.into_db()),
+        SpecData::Mel => Cow::Borrowed(&state.mel.db[..]),
     }
 }
@@ -1,15 +1,20 @@
 use egui::{Align, Button, Frame, Layout, Slider, TopBottomPanel, Ui, Vec2, Window};
 use egui_plot::{Corner, Legend, Plot};
+use fields_iter::FieldsIter;
 use lib::{
+    cfg::AnalysisConfig,
     color::Oklch,
-    state::{AnalysisState, light::LightData, loudness::LoudnessData, power::PowerData},
+    state::{
+        AnalysisState, beat::BeatData, drums::DrumsData, light::LightData, loudness::LoudnessData,
+        melody::MelodyData, power::PowerData, spectral::Descriptors,
+    },
 };
 use puffin_egui::puffin;
 use strum::{Display, EnumIter, IntoEnumIterator};
 
 use std::convert::Infallible;
 
-use crate::util::{DataVec, uninteractable_plot};
+use crate::util::{DataVec, struct_combobox, uninteractable_plot};
 
 use serde::{Deserialize, Serialize};
 
@@ -37,6 +42,12 @@ pub struct Graph {
     pdata: DataVec<'static, PowerData, Infallible>,
     ldata: DataVec<'static, LightData, Infallible>,
     odata: DataVec<'static, LoudnessData, Infallible>,
+    bdata: DataVec<'static, BeatData, Infallible>,
+    mdata: DataVec<'static, MelodyData, Infallible>,
+    ddata: DataVec<'static, DrumsData, Infallible>,
+    sdata: DataVec<'static, Descriptors, Infallible>,
+    /// Field of `Descriptors` shown in `Tab::Spectral`
+    spectral: &'static str,
     len: usize,
 }
 
@@ -50,6 +61,11 @@ enum Tab {
     Loudness,
     OctavePower,
     Notes,
+    Tuning,
+    Beat,
+    Melody,
+    Spectral,
+    Drums,
 }
 
 // for OctavePower and Octave
@@ -74,12 +90,18 @@ impl Graph {
             pdata: DataVec::new(len),
             ldata: DataVec::new(len),
             odata: DataVec::new(len),
+            bdata: DataVec::new(len),
+            mdata: DataVec::new(len),
+            ddata: DataVec::new(len),
+            sdata: DataVec::new(len),
+            spectral: "centroid",
             len,
         }
     }
 
-    pub fn ui(&mut self, state: &mut GraphState, ctx: &egui::Context) {
+    pub fn ui(&mut self, state: &mut GraphState, cfg: &AnalysisConfig, ctx: &egui::Context) {
         puffin::profile_function!();
+        let bass = cfg.light.bass_band.as_str();
         let mut window = state.window;
         let ui = |ui: &mut Ui| {
             ui.horizontal(|ui| {
@@ -157,9 +179,9 @@ impl Graph {
                         );
                         plot_ui.line(
                             self.pdata
-                                .derive(|d| d.p_bass_power.val)
+                                .derive(|d| d.band(bass).map_or(0.0, |b| b.val))
                                 .line()
-                                .name("bass")
+                                .name(bass)
                                 .color(Oklch::LIGHT.green()),
                         );
                         plot_ui.line(
@@ -189,9 +211,11 @@ impl Graph {
                         );
                         plot_ui.line(
                             self.pdata
-                                .derive(|d| d.p_bass_power.val / d.p_filtered_power.val)
+                                .derive(|d| {
+                                    d.band(bass).map_or(0.0, |b| b.val) / d.p_filtered_power.val
+                                })
                                 .line()
-                                .name("bass")
+                                .name(bass)
                                 .color(Oklch::LIGHT.green()),
                         )
                     }),
@@ -230,6 +254,13 @@ impl Graph {
                                 .name("Momentary")
                                 .color(Oklch::LIGHT.red()),
                         );
+                        plot_ui.line(
+                            self.odata
+                                .derive(|d| d.integrated)
+                                .line()
+                                .name("Integrated")
+                                .color(Oklch::LIGHT.sky_blue()),
+                        );
                     }),
                 Tab::OctavePower => {
                     self.default_plot("octave_power", state.legend)
@@ -258,6 +289,103 @@ impl Graph {
                             )
                         }
                     }),
+                Tab::Tuning => self
+                    .default_plot("tuning", state.legend)
+                    .include_y(-50.0)
+                    .include_y(50.0)
+                    .show(ui, |plot_ui| {
+                        plot_ui.line(
+                            self.pdata
+                                .derive(|d| d.tuning)
+                                .line()
+                                .name("Cents")
+                                .color(Oklch::LIGHT.sky_blue()),
+                        );
+                    }),
+                Tab::Beat => self.default_plot("beat", state.legend).show(ui, |plot_ui| {
+                    plot_ui.line(
+                        self.bdata
+                            .derive(|d| d.phase)
+                            .line()
+                            .name("Phase")
+                            .color(Oklch::LIGHT.yellow()),
+                    );
+                    plot_ui.line(
+                        self.bdata
+                            .derive(|d| d.confidence)
+                            .line()
+                            .name("Confidence")
+                            .color(Oklch::LIGHT.green()),
+                    );
+                    plot_ui.line(
+                        self.bdata
+                            .derive(|d| d.bpm / 100.0)
+                            .line()
+                            .name("BPM / 100")
+                            .color(Oklch::LIGHT.red()),
+                    );
+                }),
+                Tab::Melody => self
+                    .default_plot("melody", state.legend)
+                    .show(ui, |plot_ui| {
+                        plot_ui.line(
+                            self.mdata
+                                .derive(|d| if d.voiced { d.midi / 100.0 } else { 0.0 })
+                                .line()
+                                .name("MIDI / 100")
+                                .color(Oklch::LIGHT.sky_blue()),
+                        );
+                        plot_ui.line(
+                            self.mdata
+                                .derive(|d| d.confidence)
+                                .line()
+                                .name("Confidence")
+                                .color(Oklch::LIGHT.green()),
+                        );
+                    }),
+                Tab::Spectral => {
+                    struct_combobox(
+                        ui,
+                        &Descriptors::default(),
+                        "spectral_combo",
+                        "Descriptor",
+                        &mut self.spectral,
+                    );
+                    let name = self.spectral;
+                    self.default_plot("spectral", state.legend)
+                        .show(ui, |plot_ui| {
+                            plot_ui.line(
+                                self.sdata
+                                    .derive(move |d| {
+                                        FieldsIter::new(d)
+                                            .find(|(n, _)| *n == name)
+                                            .and_then(|(_, v)| v.downcast_ref::<f32>().copied())
+                                            .unwrap_or_default()
+                                    })
+                                    .line()
+                                    .name(name)
+                                    .color(Oklch::LIGHT.sky_blue()),
+                            );
+                        })
+                }
+                Tab::Drums => self
+                    .default_plot("drums", state.legend)
+                    .show(ui, |plot_ui| {
+                        let drums: [(_, _, fn(&DrumsData) -> f32); 3] = [
+                            ("Kick", Oklch::LIGHT.red(), |d| d.kick.envelope),
+                            ("Snare", Oklch::LIGHT.yellow(), |d| d.snare.envelope),
+                            ("Hi-hat", Oklch::LIGHT.sky_blue(), |d| d.hihat.envelope),
+                        ];
+                        for (name, color, envelope) in drums {
+                            plot_ui.line(
+                                self.ddata
+                                    .derive(move |d| envelope(d) / 10.0)
+                                    .line()
+                                    .name(format!("{name} / 10"))
+                                    .color(color),
+                            );
+                        }
+                    }),
             };
             window
         };
@@ -282,6 +410,10 @@ impl Graph {
         self.pdata.push(state.power.clone());
         self.ldata.push(state.light.clone());
         self.odata.push(state.loudness.clone());
+        self.bdata.push(state.beat.clone());
+        self.mdata.push(state.melody.clone());
+        self.ddata.push(state.drums.clone());
+        self.sdata.push(state.spectral.descriptors);
     }
 
     fn default_plot<'a>(&self, id: impl std::hash::Hash, legend: bool) -> Plot<'a> {
@@ -1,5 +1,5 @@
 use criterion::{Criterion, criterion_group, criterion_main};
-use lib::{cfg::AnalysisConfig, state::AnalysisState};
+use lib::{Analyzer, cfg::AnalysisConfig, state::fft::FftData};
 use std::{
     fs,
     time::{Duration, Instant},
@@ -11,23 +11,18 @@ pub fn analysis(c: &mut Criterion) {
             .ok()
             .and_then(|s| toml::from_str::<AnalysisConfig>(&s).ok())
             .unwrap_or_default();
-        let mut ebur = cfg.ebur();
 
         b.iter_custom(|reps| {
-            let mut state = AnalysisState::blank(&cfg);
+            let mut analyzer = Analyzer::new(cfg.clone(), 1, cfg.fft.sample_rate as u32);
             let mut duration = Duration::ZERO;
             for _ in 0..reps {
-                let data = rand::random_iter()
+                let data = rand::random_iter::<i16>()
                     .take(cfg.fft.hop_len)
                     .collect::<Vec<_>>();
 
                 let start = Instant::now();
-                state = std::hint::black_box(AnalysisState::from_prev(
-                    &cfg,
-                    state,
-                    data.iter().cloned(),
-                    &mut ebur,
-                ));
+                analyzer.push(&data);
+                std::hint::black_box(analyzer.next_frame());
                 duration += start.elapsed();
             }
             duration
@@ -35,5 +30,20 @@ pub fn analysis(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, analysis);
+pub fn fft(c: &mut Criterion) {
+    c.bench_function("FftData::advance", |b| {
+        let cfg = AnalysisConfig::default();
+        let samples = rand::random_iter::<f32>()
+            .take(cfg.fft.frame_len)
+            .collect::<Vec<_>>();
+
+        let mut fft = Some(FftData::blank(&cfg));
+        b.iter(|| {
+            let data = fft.take().unwrap().advance(&cfg, samples.iter().cloned());
+            fft = Some(std::hint::black_box(data));
+        });
+    });
+}
+
+criterion_group!(benches, analysis, fft);
 criterion_main!(benches);
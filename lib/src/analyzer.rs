@@ -0,0 +1,572 @@
+use std::collections::VecDeque;
+
+use ebur128::EbuR128;
+use fields_iter::FieldsIterMut;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cfg::AnalysisConfig,
+    easing::EasingFunction,
+    state::{AnalysisState, hps, onset::Onset, paint::PaintData},
+    unit::Sample,
+};
+
+/// Drives [`AnalysisState::from_prev`] so callers don't have to.
+///
+/// Interleaved samples can be pushed in chunks of any length; they are
+/// buffered until a full hop is available. Finished frames are then handed
+/// out one hop at a time by [`Analyzer::next_frame`]:
+///
+/// ```ignore
+/// analyzer.push(&samples);
+/// while let Some(state) = analyzer.next_frame() {
+///     // ...
+/// }
+/// // only needed with `LookaheadConfig::enabled`
+/// while let Some(state) = analyzer.finish() {
+///     // ...
+/// }
+/// ```
+pub struct Analyzer {
+    cfg: AnalysisConfig,
+    ebur: EbuR128,
+    state: AnalysisState,
+    channels: usize,
+    input: VecDeque<f32>,
+    lookahead: Option<Lookahead>,
+}
+
+impl Analyzer {
+    /// The analysis runs at the `sample_rate` of the source, overriding
+    /// `FftConfig::sample_rate`, so frequencies stay correct for any input.
+    pub fn new(mut cfg: AnalysisConfig, channels: usize, sample_rate: u32) -> Self {
+        cfg.fft.sample_rate = sample_rate as usize;
+        Self {
+            ebur: cfg.ebur(channels),
+            state: AnalysisState::blank(&cfg, channels),
+            channels,
+            input: VecDeque::new(),
+            lookahead: Lookahead::new(&cfg),
+            cfg,
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.cfg.fft.sample_rate as u32
+    }
+
+    pub fn cfg(&self) -> &AnalysisConfig {
+        &self.cfg
+    }
+
+    /// Copies the parts of the config that can change mid-analysis: the
+    /// loudness settings and the HPS mask factors.
+    ///
+    /// Everything else sizes the saved state (`fft`, `spectrogram`, `light`,
+    /// `lookahead`, ...) and needs a new `Analyzer` instead.
+    pub fn set_cfg(&mut self, cfg: &AnalysisConfig) {
+        self.cfg.loudness.clone_from(&cfg.loudness);
+        self.cfg.hps.h_factor = cfg.hps.h_factor;
+        self.cfg.hps.p_factor = cfg.hps.p_factor;
+    }
+
+    /// The state of the last finished frame
+    pub fn state(&self) -> &AnalysisState {
+        match &self.lookahead {
+            Some(Lookahead {
+                output: Some(output),
+                ..
+            }) => output,
+            _ => &self.state,
+        }
+    }
+
+    /// The state the next frame is analyzed from, which is ahead of
+    /// [`Analyzer::state`] with `LookaheadConfig::enabled`
+    pub fn state_mut(&mut self) -> &mut AnalysisState {
+        &mut self.state
+    }
+
+    /// Buffer interleaved samples to be analyzed by [`Analyzer::next_frame`]
+    pub fn push<S: Sample>(&mut self, samples: &[S]) {
+        self.input.extend(samples.iter().map(|s| s.to_f32()));
+    }
+
+    /// Analyzes the next hop, or returns `None` if not enough samples have
+    /// been pushed yet.
+    ///
+    /// With `LookaheadConfig::enabled` the frames come out late by the delay
+    /// described there, and the last ones are only handed out by
+    /// [`Analyzer::finish`].
+    pub fn next_frame(&mut self) -> Option<&AnalysisState> {
+        let len = self.cfg.fft.hop_len * self.channels;
+        loop {
+            if self.input.len() < len {
+                return None;
+            }
+
+            let hop = self.input.drain(..len).collect::<Vec<_>>();
+            take_mut::take_or_recover(
+                &mut self.state,
+                || AnalysisState::blank(&self.cfg, self.channels),
+                |s| AnalysisState::from_prev(&self.cfg, s, &hop, &mut self.ebur),
+            );
+            let Some(lookahead) = &mut self.lookahead else {
+                return Some(&self.state);
+            };
+            lookahead.push(&self.cfg, self.state.clone());
+            if lookahead.ready(&self.cfg) {
+                break;
+            }
+        }
+        self.lookahead.as_mut().map(|l| l.emit(&self.cfg))
+    }
+
+    /// Hands out the frames still held back for the lookahead once all
+    /// samples have been pushed. Always `None` without
+    /// `LookaheadConfig::enabled`.
+    pub fn finish(&mut self) -> Option<&AnalysisState> {
+        let lookahead = self.lookahead.as_mut()?;
+        (lookahead.emitted < lookahead.count).then(|| lookahead.emit(&self.cfg))
+    }
+
+    /// Throws away all buffered samples and starts over from a blank state
+    pub fn reset(&mut self) {
+        self.ebur = self.cfg.ebur(self.channels);
+        self.state = AnalysisState::blank(&self.cfg, self.channels);
+        self.input.clear();
+        self.lookahead = Lookahead::new(&self.cfg);
+    }
+}
+
+/// Recenters the causal states and smooths them with the ones around them.
+///
+/// The states go through three centered windows one after the other: the FFT
+/// frame, the time median of the HPS, which is recentered bin by bin from the
+/// states ahead, and the smoothing of the light over `LookaheadConfig::hops`.
+struct Lookahead {
+    /// Causal states that are still needed to center the next one
+    causal: VecDeque<AnalysisState>,
+    /// States with a centered HPS, the first is number `first`
+    centered: VecDeque<AnalysisState>,
+    first: usize,
+    /// Number of causal states analyzed so far
+    count: usize,
+    /// Number of states handed out so far
+    emitted: usize,
+    paint: PaintData,
+    output: Option<AnalysisState>,
+}
+
+impl Lookahead {
+    fn new(cfg: &AnalysisConfig) -> Option<Self> {
+        cfg.lookahead.enabled.then(|| Self {
+            causal: VecDeque::new(),
+            centered: VecDeque::new(),
+            first: 0,
+            count: 0,
+            emitted: 0,
+            paint: PaintData::blank(cfg),
+            output: None,
+        })
+    }
+
+    /// The frame of a state is centered this many hops before its end
+    fn frame_delay(cfg: &AnalysisConfig) -> usize {
+        cfg.hops() / 2
+    }
+
+    /// Hops a median of bin `i` needs after the hop it's centered on
+    fn hps_delay(cfg: &AnalysisConfig, i: usize) -> usize {
+        hps::h_filter_len(cfg, i).saturating_sub(1) / 2
+    }
+
+    /// Hops on either side the light is smoothed over
+    fn radius(cfg: &AnalysisConfig) -> usize {
+        cfg.lookahead.hops.max(1)
+    }
+
+    /// Hops the output lags the input, which all the centered windows add up
+    /// to. The longest median is the one of the highest bin.
+    fn delay(cfg: &AnalysisConfig) -> usize {
+        Self::frame_delay(cfg) + Self::hps_delay(cfg, cfg.max_aidx() - 1) + Self::radius(cfg)
+    }
+
+    fn push(&mut self, cfg: &AnalysisConfig, state: AnalysisState) {
+        self.causal.push_back(state);
+        self.count += 1;
+        while self.causal.len() > Self::hps_delay(cfg, cfg.max_aidx() - 1) {
+            self.center(cfg);
+        }
+    }
+
+    fn ready(&self, cfg: &AnalysisConfig) -> bool {
+        self.count > self.emitted + Self::delay(cfg)
+    }
+
+    /// Centers the HPS of the oldest causal state on its hop and redoes the
+    /// stages after it. Past the end, the last state stands in for the ones
+    /// the medians would need.
+    fn center(&mut self, cfg: &AnalysisConfig) {
+        let mut state = self.causal.pop_front().unwrap();
+        let last = self.causal.len();
+        let vocal_activity = self
+            .centered
+            .back()
+            .map_or(state.hps.vocal_activity, |s| s.hps.vocal_activity);
+        let ahead = |i: usize| match Self::hps_delay(cfg, i).min(last) {
+            0 => None,
+            d => Some(self.causal[d - 1].hps.h_enhanced[i]),
+        };
+        let hps = &mut state.hps;
+        hps.h_enhanced.update(|i, &h| ahead(i).unwrap_or(h));
+        hps.separate(cfg, &state.fft, vocal_activity);
+        let state = match self.centered.back() {
+            Some(prev) => state.rederive(cfg, prev),
+            // nothing to carry over yet
+            None => state,
+        };
+        self.centered.push_back(state);
+    }
+
+    /// Hands out the centered state whose frame is centered on the next hop,
+    /// with its onsets lined up and its light smoothed
+    fn emit(&mut self, cfg: &AnalysisConfig) -> &AnalysisState {
+        if self.emitted + Self::delay(cfg) >= self.count {
+            // the input ended, so what's left can be centered as far as it goes
+            while !self.causal.is_empty() {
+                self.center(cfg);
+            }
+        }
+        let radius = Self::radius(cfg);
+        let last = self.first + self.centered.len() - 1;
+        let center = (self.emitted + Self::frame_delay(cfg)).min(last);
+        let at = |i: usize| &self.centered[i - self.first];
+
+        let mut state = at(center).clone();
+        state.hop = self.emitted + 1;
+
+        // peaks are picked a hop late
+        let next = at((center + 1).min(last));
+        let time = state.hop as f32 * cfg.hop_duration();
+        let align = |onset: Option<Onset>| onset.map(|o| Onset { time, ..o });
+        state.onset.onset = align(next.onset.onset);
+        state.drums.kick.onset = align(next.drums.kick.onset);
+        state.drums.snare.onset = align(next.drums.snare.onset);
+        state.drums.hihat.onset = align(next.drums.hihat.onset);
+
+        let light = &mut state.light;
+        let ahead = (center + 1..=(center + radius).min(last)).map(|i| &at(i).light);
+        light.smooth_backward(cfg, ahead);
+        // zero-phase moving average instead of the trailing one
+        let window = (center.saturating_sub(radius).max(self.first)..=(center + radius).min(last))
+            .map(at)
+            .collect::<Vec<_>>();
+        for (i, note) in light.notes.iter_mut().enumerate() {
+            let sum = window
+                .iter()
+                .map(|s| (s.power.octave_power[i] * 10.0 + 1.0).log2())
+                .sum::<f32>();
+            note.fill(sum / window.len() as f32);
+        }
+
+        // paint from the light of this hop rather than the one before
+        FieldsIterMut::new(&mut state.easing)
+            .filter_map(|(_, f)| f.downcast_mut::<EasingFunction>())
+            .for_each(|f| f.last_x.clear());
+        state.paint = self.paint.clone().advance(
+            &cfg.paint,
+            &mut state.easing,
+            &state.light,
+            &state.power,
+            &state.loudness,
+        );
+        self.paint = state.paint.clone();
+
+        self.emitted += 1;
+        let keep = (self.emitted + Self::frame_delay(cfg)).saturating_sub(radius);
+        while self.first < keep.min(last) {
+            self.centered.pop_front();
+            self.first += 1;
+        }
+        self.output.insert(state)
+    }
+}
+
+/// Delays the output so every state can be lined up with the audio it was
+/// computed from, with the HPS medians centered on it and the light smoothed
+/// with the states around it. Only useful when the whole track is available
+/// up front, like in the renderer, since the delay is half a frame plus half
+/// the longest HPS median plus `hops`.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct LookaheadConfig {
+    pub enabled: bool,
+    /// Hops on either side the light is smoothed over
+    pub hops: usize,
+}
+
+impl Default for LookaheadConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            hops: 2,
+        }
+    }
+}
+
+#[test]
+fn test_chunk_sizes() {
+    let cfg = AnalysisConfig::default();
+    let hop_len = cfg.fft.hop_len;
+    let samples = (0..hop_len * 8)
+        .map(|i| (f32::sin(i as f32 * 0.05) * 8000.0) as i16)
+        .collect::<Vec<_>>();
+
+    let frames = |chunk_len: usize| {
+        let mut analyzer = Analyzer::new(cfg.clone(), 1, 44100);
+        let mut out = Vec::new();
+        for chunk in samples.chunks(chunk_len) {
+            analyzer.push(chunk);
+            while let Some(state) = analyzer.next_frame() {
+                out.push(state.fft.db.to_vec());
+            }
+        }
+        out
+    };
+
+    let expected = frames(hop_len);
+    assert_eq!(expected.len(), 8);
+    assert_eq!(frames(333), expected);
+    assert_eq!(frames(hop_len * 3), expected);
+}
+
+#[test]
+fn test_downmix() {
+    let mut cfg = AnalysisConfig::default();
+    cfg.loudness.normalize = false;
+    cfg.fft.per_channel = true;
+    let hop_len = cfg.fft.hop_len;
+    let mono = (0..hop_len * 4)
+        .map(|i| (f32::sin(i as f32 * 0.05) * 8000.0) as i16)
+        .collect::<Vec<_>>();
+    let stereo = mono.iter().flat_map(|&s| [s, s]).collect::<Vec<_>>();
+
+    let mut expected = Analyzer::new(cfg.clone(), 1, 44100);
+    expected.push(&mono);
+    let mut actual = Analyzer::new(cfg.clone(), 2, 44100);
+    actual.push(&stereo);
+    for _ in 0..4 {
+        let expected = expected.next_frame().unwrap().fft.db.to_vec();
+        let state = actual.next_frame().unwrap();
+        assert_eq!(state.fft.db.to_vec(), expected);
+        assert_eq!(state.channels.fft[0].db.to_vec(), expected);
+        assert_eq!(state.channels.fft[1].db.to_vec(), expected);
+    }
+    assert!(actual.next_frame().is_none());
+}
+
+#[test]
+fn test_sample_rate() {
+    use std::f32::consts::TAU;
+
+    let mut cfg = AnalysisConfig::default();
+    cfg.loudness.normalize = false;
+    for sample_rate in [22050, 44100, 48000, 96000] {
+        let mut analyzer = Analyzer::new(cfg.clone(), 1, sample_rate);
+        let samples = (0..sample_rate as usize / 2)
+            .map(|i| (f32::sin(i as f32 / sample_rate as f32 * 440.0 * TAU) * 8000.0) as i16)
+            .collect::<Vec<_>>();
+        analyzer.push(&samples);
+        while analyzer.next_frame().is_some() {}
+
+        let cfg = analyzer.cfg();
+        let (peak, _) = analyzer
+            .state()
+            .fft
+            .power
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.0.total_cmp(&b.1.0))
+            .unwrap();
+        let hz = cfg.idx_to_hz(peak + cfg.min_idx());
+        assert!(
+            (hz - 440.0).abs() <= cfg.idx_to_hz(1),
+            "{sample_rate}: {hz}"
+        );
+    }
+}
+
+#[test]
+fn test_low_sample_rate() {
+    use std::f32::consts::TAU;
+
+    // the spectrogram goes up to 8k, past the nyquist frequency of these
+    let mut cfg = AnalysisConfig::default();
+    cfg.cqt.enabled = true;
+    cfg.hps.vocal.enabled = true;
+    for sample_rate in [8000, 11025] {
+        let mut analyzer = Analyzer::new(cfg.clone(), 1, sample_rate);
+        let samples = (0..sample_rate as usize)
+            .map(|i| (f32::sin(i as f32 / sample_rate as f32 * 440.0 * TAU) * 8000.0) as i16)
+            .collect::<Vec<_>>();
+        analyzer.push(&samples);
+        while analyzer.next_frame().is_some() {}
+
+        let cfg = analyzer.cfg();
+        assert!(cfg.max_idx() < cfg.raw_len());
+        assert_eq!(analyzer.state().fft.power.len(), cfg.max_aidx());
+    }
+}
+
+#[test]
+fn test_f32_input() {
+    let cfg = AnalysisConfig::default();
+    let hop_len = cfg.fft.hop_len;
+    let ints = (0..hop_len * 4)
+        .map(|i| (f32::sin(i as f32 * 0.05) * 8000.0) as i16)
+        .collect::<Vec<_>>();
+    let floats = ints.iter().map(|&s| s.to_f32()).collect::<Vec<_>>();
+
+    let mut expected = Analyzer::new(cfg.clone(), 1, 44100);
+    expected.push(&ints);
+    let mut actual = Analyzer::new(cfg.clone(), 1, 44100);
+    actual.push(&floats);
+    for _ in 0..4 {
+        let expected = expected.next_frame().unwrap().fft.db.to_vec();
+        assert_eq!(actual.next_frame().unwrap().fft.db.to_vec(), expected);
+    }
+
+    // samples past full scale are not clipped
+    let mut cfg = cfg;
+    cfg.loudness.normalize = false;
+    let loud = floats.iter().map(|s| s * 8.0).collect::<Vec<_>>();
+    let mut analyzer = Analyzer::new(cfg, 1, 44100);
+    analyzer.push(&loud);
+    while analyzer.next_frame().is_some() {}
+    let max = analyzer
+        .state()
+        .buffer
+        .iter()
+        .fold(0.0, |a: f32, s| a.max(s.abs()));
+    assert!(max > 1.0);
+}
+
+#[test]
+fn test_set_cfg() {
+    let cfg = AnalysisConfig::default();
+    let mut analyzer = Analyzer::new(cfg.clone(), 1, 44100);
+
+    let mut edited = cfg.clone();
+    edited.fft.frame_len *= 2;
+    edited.fft.sample_rate = 48000;
+    edited.loudness.normalize = false;
+    edited.hps.h_factor = 3.0;
+    analyzer.set_cfg(&edited);
+
+    // the shape of the analysis stays, the tunable parts change
+    let current = analyzer.cfg();
+    assert_eq!(current.fft.frame_len, cfg.fft.frame_len);
+    assert_eq!(current.fft.sample_rate, 44100);
+    assert!(!current.loudness.normalize);
+    assert_eq!(current.hps.h_factor, 3.0);
+    analyzer.push(&vec![0i16; cfg.fft.hop_len * 2]);
+    while analyzer.next_frame().is_some() {}
+}
+
+#[test]
+fn test_lookahead() {
+    let mut cfg = AnalysisConfig::default();
+    cfg.loudness.normalize = false;
+    let sample_rate = cfg.fft.sample_rate;
+    let hop = cfg.hop_duration();
+    let clicks = [1.0, 2.0, 3.0];
+    let mut samples = vec![0.0; sample_rate * 4];
+    for time in clicks {
+        let start = (time * sample_rate as f32) as usize;
+        for (i, s) in samples[start..start + 64].iter_mut().enumerate() {
+            *s = (1.0 - i as f32 / 64.0) * if i % 2 == 0 { 0.8 } else { -0.8 };
+        }
+    }
+
+    // (frame count, times of the frames with an onset)
+    let run = |enabled: bool| {
+        let mut cfg = cfg.clone();
+        cfg.lookahead.enabled = enabled;
+        let mut analyzer = Analyzer::new(cfg, 1, sample_rate as u32);
+        analyzer.push(&samples);
+        let (mut frames, mut onsets) = (0, vec![]);
+        let mut frame = |state: &AnalysisState| {
+            frames += 1;
+            assert_eq!(state.hop, frames);
+            if state.onset.onset.is_some() {
+                onsets.push(state.hop as f32 * hop);
+            }
+        };
+        while let Some(state) = analyzer.next_frame() {
+            frame(state);
+        }
+        while let Some(state) = analyzer.finish() {
+            frame(state);
+        }
+        (frames, onsets)
+    };
+
+    let (causal_frames, causal) = run(false);
+    let (frames, aligned) = run(true);
+    assert_eq!(frames, causal_frames);
+    assert_eq!(aligned.len(), clicks.len(), "{aligned:?}");
+    assert_eq!(causal.len(), clicks.len(), "{causal:?}");
+    for ((aligned, causal), click) in aligned.iter().zip(&causal).zip(clicks) {
+        assert!((aligned - click).abs() <= hop, "{aligned} != {click}");
+        assert!(causal - click > 2.0 * hop, "{causal} {click}");
+    }
+}
+
+#[test]
+fn test_lookahead_hps() {
+    use std::f32::consts::TAU;
+
+    let mut cfg = AnalysisConfig::default();
+    cfg.loudness.normalize = false;
+    let sample_rate = cfg.fft.sample_rate;
+    let hop = cfg.hop_duration();
+    // a tone that starts a second in
+    let samples = (0..sample_rate * 3)
+        .map(|i| {
+            let t = i as f32 / sample_rate as f32;
+            if t < 1.0 {
+                0.0
+            } else {
+                f32::sin(t * 440.0 * TAU) * 0.25
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // time the harmonic part reaches half of where it ends up
+    let run = |enabled: bool| {
+        let mut cfg = cfg.clone();
+        cfg.lookahead.enabled = enabled;
+        let mut analyzer = Analyzer::new(cfg, 1, sample_rate as u32);
+        analyzer.push(&samples);
+        let mut powers = vec![];
+        while let Some(state) = analyzer.next_frame() {
+            powers.push(state.power.h_power_raw);
+        }
+        while let Some(state) = analyzer.finish() {
+            powers.push(state.power.h_power_raw);
+        }
+        let end = *powers.last().unwrap();
+        let hops = powers.iter().position(|&p| p > end / 2.0).unwrap() + 1;
+        hops as f32 * hop
+    };
+
+    let causal = run(false);
+    let centered = run(true);
+    assert!((centered - 1.0).abs() <= 2.0 * hop, "{centered}");
+    assert!(causal - 1.0 > 5.0 * hop, "{causal}");
+}
@@ -1,16 +1,35 @@
 use serde::{Deserialize, Serialize};
 
-use crate::state::{fft, hps, light, loudness, paint};
+use crate::{
+    analyzer, segment,
+    state::{
+        beat, cqt, drums, fft, harmony, hps, light, loudness, mel, melody, onset, paint, power,
+        spectral,
+    },
+};
 
 #[derive(Deserialize, Serialize, Default, Clone)]
 #[serde(default)]
 pub struct AnalysisConfig {
     pub spectrogram: SpectrogramConfig,
     pub fft: fft::FftConfig,
+    pub cqt: cqt::CqtConfig,
+    pub notes: power::NotesConfig,
+    pub tuning: power::TuningConfig,
+    pub bands: power::BandsConfig,
+    pub mel: mel::MelConfig,
+    pub spectral: spectral::SpectralConfig,
     pub hps: hps::HpsConfig,
+    pub onset: onset::OnsetConfig,
+    pub beat: beat::BeatConfig,
+    pub drums: drums::DrumsConfig,
+    pub harmony: harmony::HarmonyConfig,
+    pub melody: melody::MelodyConfig,
     pub light: light::LightConfig,
     pub paint: paint::PaintConfig,
     pub loudness: loudness::LoudnessConfig,
+    pub segment: segment::SegmentConfig,
+    pub lookahead: analyzer::LookaheadConfig,
 }
 
 impl AnalysisConfig {
@@ -18,11 +37,16 @@ impl AnalysisConfig {
         self.fft.frame_len as f32 / self.fft.sample_rate as f32
     }
 
+    pub const fn hop_duration(&self) -> f32 {
+        self.fft.hop_len as f32 / self.fft.sample_rate as f32
+    }
+
     pub const fn idx_to_hz(&self, i: usize) -> f32 {
         i as f32 / self.frame_duration()
     }
 
-    /// Assumes a sample rate of 44.1kHz
+    /// Uses `FftConfig::sample_rate`, so this is only correct if it matches
+    /// the input (which `Analyzer` takes care of)
     pub const fn hz_to_idx(&self, hz: f32) -> usize {
         (hz * self.frame_duration()) as usize
     }
@@ -31,8 +55,12 @@ impl AnalysisConfig {
         self.hz_to_idx(self.spectrogram.min_frequency)
     }
 
+    /// Capped at the highest bin the FFT has, for sources with a Nyquist
+    /// frequency below `SpectrogramConfig::max_frequency`
     pub const fn max_idx(&self) -> usize {
-        self.hz_to_idx(self.spectrogram.max_frequency)
+        let idx = self.hz_to_idx(self.spectrogram.max_frequency);
+        let last = self.raw_len() - 1;
+        if idx < last { idx } else { last }
     }
 
     pub const fn idx_range(&self) -> usize {
@@ -50,15 +78,35 @@ impl AnalysisConfig {
         self.idx_to_aidx(self.max_idx())
     }
 
+    /// Number of non-negative frequencies out of the FFT
+    pub const fn raw_len(&self) -> usize {
+        self.fft.frame_len / 2 + 1
+    }
+
     pub fn hops(&self) -> usize {
         self.fft.frame_len / self.fft.hop_len
     }
 
-    pub fn ebur(&self) -> ebur128::EbuR128 {
+    /// Checks the references between sections, which serde can't
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.bands.iter().any(|b| b.name == self.light.bass_band) {
+            return Err(format!(
+                "light.bass_band {:?} doesn't name any of the bands",
+                self.light.bass_band
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn ebur(&self, channels: usize) -> ebur128::EbuR128 {
         ebur128::EbuR128::new(
-            1,
+            channels as u32,
             self.fft.sample_rate as u32,
-            ebur128::Mode::S | ebur128::Mode::M,
+            ebur128::Mode::S
+                | ebur128::Mode::M
+                | ebur128::Mode::I
+                | ebur128::Mode::LRA
+                | ebur128::Mode::HISTOGRAM,
         ).unwrap()
     }
 }
@@ -1,5 +1,7 @@
+use std::collections::VecDeque;
+
 use emath::Vec2;
-use fields_iter::FieldsInspect;
+use fields_iter::{FieldsInspect, FieldsIterMut};
 use serde::{Deserialize, Serialize};
 
 use crate::color::Oklch;
@@ -13,6 +15,15 @@ pub struct EasingFunctions {
     pub octave: EasingFunction,
 }
 
+impl EasingFunctions {
+    /// Keep the `min` and `max` every adaptive function has learned so far
+    pub fn freeze(&mut self) {
+        FieldsIterMut::new(self)
+            .filter_map(|(_, f)| f.downcast_mut::<EasingFunction>())
+            .for_each(|f| f.adaptive.frozen = true);
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct EasingFunction {
     pub min: f32,
@@ -24,7 +35,9 @@ pub struct EasingFunction {
     pub last_x: Vec<f32>,
     #[serde(with="oklch")]
     #[serde(default)]
-    pub colors: Option<Vec<Oklch>>
+    pub colors: Option<Vec<Oklch>>,
+    #[serde(default)]
+    pub adaptive: Adaptive,
 }
 
 mod oklch {
@@ -64,6 +77,7 @@ impl Default for EasingFunction {
             }),
             last_x: vec![],
             colors: None,
+            adaptive: Default::default(),
         }
     }
 }
@@ -71,6 +85,9 @@ impl Default for EasingFunction {
 impl EasingFunction {
     /// Ease x and output y in the domain [0, 1]
     pub fn ease_normalize(&mut self, x: f32) -> f32 {
+        if self.adaptive.learning() {
+            self.adaptive.current.push(x);
+        }
         let x = ((x - self.min) / self.range()).clamp(0.0, 1.0);
         self.last_x.push(x);
         let y = self.variant.solve(x).clamp(0.0, 1.0);
@@ -85,6 +102,140 @@ impl EasingFunction {
     pub fn range(&self) -> f32 {
         self.max - self.min
     }
+
+    /// Call once every hop. If the function is learning, `min` and `max` are
+    /// updated with the x values since the last call.
+    pub fn next_hop(&mut self) {
+        self.last_x.clear();
+        if !self.adaptive.learning() {
+            self.adaptive.current.clear();
+            return;
+        }
+        if let Some((min, max)) = self.adaptive.next_hop()
+            && max > min
+        {
+            self.min = min;
+            self.max = max;
+        }
+    }
+}
+
+/// Number of quantiles every hop is summarized with
+const QUANTILES: usize = 16;
+
+/// Learns `min` and `max` of an `EasingFunction` from running percentiles of
+/// the x values it gets
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Adaptive {
+    pub enabled: bool,
+    /// Keep the learned `min` and `max` instead of learning
+    pub frozen: bool,
+    /// Percentile of x that becomes `min`, from 0 to 1
+    pub low: f32,
+    /// Percentile of x that becomes `max`, from 0 to 1
+    pub high: f32,
+    /// Number of hops the percentiles are taken over
+    pub window: usize,
+    /// Weight of every hop relative to the one after it
+    pub decay: f32,
+    /// x values of the current hop
+    #[serde(skip)]
+    current: Vec<f32>,
+    /// Quantiles of the x values of past hops, newest first
+    #[serde(skip)]
+    hops: VecDeque<[f32; QUANTILES]>,
+    /// Everything in `hops` in ascending order, with the number of the hop it
+    /// came from
+    #[serde(skip)]
+    sorted: Vec<(f32, usize)>,
+    /// Number of hops summarized so far
+    #[serde(skip)]
+    count: usize,
+}
+
+impl Default for Adaptive {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            frozen: false,
+            low: 0.05,
+            high: 0.95,
+            // ~10s at 1024 samples/hop and 44.1k samples/s
+            window: 430,
+            decay: 0.995,
+            current: vec![],
+            hops: VecDeque::new(),
+            sorted: vec![],
+            count: 0,
+        }
+    }
+}
+
+impl Adaptive {
+    pub fn learning(&self) -> bool {
+        self.enabled && !self.frozen
+    }
+
+    /// Summarizes the x values of the last hop and returns the percentiles
+    /// over the window
+    fn next_hop(&mut self) -> Option<(f32, f32)> {
+        if !self.current.is_empty() {
+            self.current.sort_by(f32::total_cmp);
+            let n = self.current.len();
+            let quantiles: [f32; QUANTILES] =
+                std::array::from_fn(|q| self.current[(2 * q + 1) * n / (2 * QUANTILES)]);
+            self.current.clear();
+            for x in quantiles {
+                let i = self.position(x, self.count);
+                self.sorted.insert(i, (x, self.count));
+            }
+            self.hops.push_front(quantiles);
+            self.count += 1;
+        }
+        while self.hops.len() > self.window {
+            let hop = self.count - self.hops.len();
+            for x in self.hops.pop_back().unwrap() {
+                let i = self.position(x, hop);
+                self.sorted.remove(i);
+            }
+        }
+        if self.hops.is_empty() {
+            return None;
+        }
+
+        // weight of every hop by its age
+        let weights = std::iter::successors(Some(1f32), |w| Some(w * self.decay))
+            .take(self.hops.len())
+            .collect::<Vec<_>>();
+        let weight = |hop: usize| weights[self.count - 1 - hop];
+        let total = weights.iter().sum::<f32>() * QUANTILES as f32;
+        // the smallest x with at least `p` of the weight at or below it,
+        // walking in from the closer end
+        let percentile = |p: f32| {
+            let mut sum = 0.0;
+            if p <= 0.5 {
+                self.sorted.iter().find(|&&(_, hop)| {
+                    sum += weight(hop);
+                    sum >= p * total
+                })
+            } else {
+                self.sorted.iter().rev().find(|&&(_, hop)| {
+                    sum += weight(hop);
+                    sum > (1.0 - p) * total
+                })
+            }
+            .unwrap_or(self.sorted.last().unwrap())
+            .0
+        };
+        Some((percentile(self.low), percentile(self.high)))
+    }
+
+    /// Index of `x` from hop number `hop` in `sorted`
+    fn position(&self, x: f32, hop: usize) -> usize {
+        self.sorted
+            .partition_point(|&(y, h)| y.total_cmp(&x).then(h.cmp(&hop)).is_lt())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -188,3 +339,77 @@ fn test_cubic() {
     assert_float_eq(bez.solve(0.0), 0.0);
     assert_float_eq(bez.solve(1.0), 1.0);
 }
+
+#[test]
+fn test_adaptive() {
+    let mut ease = EasingFunction::default();
+    ease.adaptive.enabled = true;
+    for hop in 0..1000 {
+        ease.next_hop();
+        for i in 0..10 {
+            ease.ease_normalize(10.0 + ((hop * 10 + i) % 100) as f32 / 10.0);
+        }
+    }
+    ease.next_hop();
+    assert!((ease.min - 10.5).abs() < 0.3, "{}", ease.min);
+    assert!((ease.max - 19.5).abs() < 0.3, "{}", ease.max);
+
+    let mut easing = EasingFunctions {
+        note: ease,
+        ..Default::default()
+    };
+    easing.freeze();
+    let mut ease: EasingFunction = toml::from_str(&toml::to_string(&easing.note).unwrap()).unwrap();
+    let (min, max) = (ease.min, ease.max);
+    assert!(ease.adaptive.frozen);
+    for _ in 0..100 {
+        ease.next_hop();
+        ease.ease_normalize(0.0);
+    }
+    assert_eq!((ease.min, ease.max), (min, max));
+}
+
+#[test]
+fn test_adaptive_window() {
+    let mut adaptive = Adaptive {
+        enabled: true,
+        window: 50,
+        ..Default::default()
+    };
+    for hop in 0..200 {
+        if hop == 120 {
+            adaptive.window = 20;
+        }
+        adaptive
+            .current
+            .extend((0..40).map(|i| ((hop * 37 + i * 11) % 101) as f32));
+        let (low, high) = adaptive.next_hop().unwrap();
+        assert_eq!(adaptive.sorted.len(), adaptive.hops.len() * QUANTILES);
+
+        // sort everything like it used to
+        let mut weighted = adaptive
+            .hops
+            .iter()
+            .enumerate()
+            .flat_map(|(age, quantiles)| {
+                let weight = adaptive.decay.powi(age as i32);
+                quantiles.iter().map(move |&x| (x, weight))
+            })
+            .collect::<Vec<_>>();
+        weighted.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let total = weighted.iter().map(|(_, w)| w).sum::<f32>();
+        let percentile = |p: f32| {
+            let mut sum = 0.0;
+            weighted
+                .iter()
+                .find(|(_, w)| {
+                    sum += w;
+                    sum >= p * total
+                })
+                .unwrap()
+                .0
+        };
+        assert_eq!(low, percentile(adaptive.low), "{hop}");
+        assert_eq!(high, percentile(adaptive.high), "{hop}");
+    }
+}
@@ -2,14 +2,17 @@
 #![feature(anonymous_lifetime_in_impl_trait)]
 #![feature(let_chains)]
 
+pub mod analyzer;
 pub mod cfg;
 pub mod color;
 pub mod easing;
+pub mod segment;
 // pub mod prof;
 pub mod state;
 pub mod unit;
 pub mod util;
 
+pub use analyzer::Analyzer;
 pub use rustfft::{Fft, FftDirection, FftPlanner, num_complex::Complex};
 pub use emath::Vec2;
 pub use ebur128;
@@ -0,0 +1,438 @@
+//! Offline song structure segmentation.
+//!
+//! Feed every [`AnalysisState`] of a track to a [`Segmenter`] and call
+//! [`Segmenter::finish`] at the end. Section boundaries are the peaks of a
+//! novelty curve from a checkerboard kernel slid along the diagonal of the
+//! self-similarity matrix (Foote, 2000). Segments that sound alike get the
+//! same label, and some simple energy rules guess what part of the song each
+//! one is.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{cfg::AnalysisConfig, state::AnalysisState, util::profile_function};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmentKind {
+    Intro,
+    Verse,
+    Chorus,
+    Drop,
+    Outro,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Segment {
+    /// Seconds from the start of the track
+    pub start: f32,
+    pub end: f32,
+    /// Segments with the same label sound alike, "A" is the first one heard
+    pub label: String,
+    pub kind: SegmentKind,
+    /// Mean loudness relative to the loudest segment, from 0 to 1
+    pub energy: f32,
+}
+
+/// Everything `Segmenter::finish` found, serialized as `[[segments]]`
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Segments {
+    pub segments: Vec<Segment>,
+}
+
+const CHROMA: usize = 12;
+const TIMBRE: usize = 5;
+const DIMS: usize = CHROMA + TIMBRE + 1;
+
+/// What the segmentation looks at from every hop
+#[derive(Clone, Copy, Debug)]
+struct Features {
+    chroma: [f32; CHROMA],
+    /// Spectral centroid, bandwidth, rolloff, flatness and zero crossing rate
+    timbre: [f32; TIMBRE],
+    /// Momentary loudness in LUFS
+    loudness: f32,
+}
+
+impl Features {
+    fn from_state(state: &AnalysisState) -> Self {
+        let d = &state.spectral.descriptors;
+        Self {
+            chroma: state.harmony.chroma,
+            timbre: [
+                d.centroid,
+                d.bandwidth,
+                d.rolloff,
+                d.flatness,
+                d.zero_crossing_rate,
+            ],
+            loudness: state.loudness.m.max(-70.0) as f32,
+        }
+    }
+}
+
+pub struct Segmenter {
+    features: Vec<Features>,
+    hop_duration: f32,
+}
+
+impl Segmenter {
+    /// `cfg` should be the one of the `Analyzer`, so the hop duration matches
+    /// the sample rate of the track
+    pub fn new(cfg: &AnalysisConfig) -> Self {
+        Self {
+            features: Vec::new(),
+            hop_duration: cfg.hop_duration(),
+        }
+    }
+
+    pub fn push(&mut self, state: &AnalysisState) {
+        self.features.push(Features::from_state(state));
+    }
+
+    pub fn finish(self, cfg: &AnalysisConfig) -> Segments {
+        profile_function!();
+        let c = &cfg.segment;
+        let block_len = ((c.resolution / self.hop_duration).round() as usize).max(1);
+        let block_duration = block_len as f32 * self.hop_duration;
+        let blocks = self
+            .features
+            .chunks(block_len)
+            .map(average)
+            .collect::<Vec<_>>();
+        if blocks.is_empty() {
+            return Segments::default();
+        }
+
+        let vectors = standardize(cfg, &blocks);
+        let novelty = novelty(&vectors, (c.kernel / 2.0 / block_duration).round() as usize);
+        let boundaries = peaks(
+            &novelty,
+            c.threshold,
+            (c.min_length / block_duration).round() as usize,
+        );
+
+        // segments as ranges of blocks
+        let edges = [0]
+            .into_iter()
+            .chain(boundaries)
+            .chain([blocks.len()])
+            .collect::<Vec<_>>();
+        let ranges = edges.windows(2).map(|e| e[0]..e[1]).collect::<Vec<_>>();
+
+        let power = ranges
+            .iter()
+            .map(|r| {
+                let sum = blocks[r.clone()]
+                    .iter()
+                    .map(|b| 10f32.powf(b.loudness / 10.0))
+                    .sum::<f32>();
+                sum / r.len() as f32
+            })
+            .collect::<Vec<_>>();
+        let max_power = power.iter().cloned().fold(0.0, f32::max);
+        let energy = power
+            .iter()
+            .map(|p| if max_power > 0.0 { p / max_power } else { 0.0 })
+            .collect::<Vec<_>>();
+
+        let means = ranges
+            .iter()
+            .map(|r| {
+                let mut mean = [0.0; DIMS];
+                for v in &vectors[r.clone()] {
+                    mean.iter_mut().zip(v).for_each(|(m, x)| *m += x);
+                }
+                mean
+            })
+            .collect::<Vec<_>>();
+        let labels = labels(&means, c.label_similarity);
+        let kinds = kinds(cfg, &labels, &energy);
+
+        let segments = ranges
+            .iter()
+            .zip(labels)
+            .zip(kinds)
+            .zip(energy)
+            .map(|(((r, label), kind), energy)| Segment {
+                start: r.start as f32 * block_duration,
+                end: r.end as f32 * block_duration,
+                label: label_name(label),
+                kind,
+                energy,
+            })
+            .collect();
+        Segments { segments }
+    }
+}
+
+fn average(features: &[Features]) -> Features {
+    let n = features.len() as f32;
+    let mut avg = Features {
+        chroma: [0.0; CHROMA],
+        timbre: [0.0; TIMBRE],
+        loudness: 0.0,
+    };
+    for f in features {
+        avg.chroma
+            .iter_mut()
+            .zip(f.chroma)
+            .for_each(|(a, x)| *a += x / n);
+        avg.timbre
+            .iter_mut()
+            .zip(f.timbre)
+            .for_each(|(a, x)| *a += x / n);
+        avg.loudness += f.loudness / n;
+    }
+    avg
+}
+
+/// Turns every feature into a z-score over the whole track and weights the
+/// groups so each one counts as much as its weight, however many values it
+/// has
+fn standardize(cfg: &AnalysisConfig, blocks: &[Features]) -> Vec<[f32; DIMS]> {
+    let c = &cfg.segment;
+    let mut vectors = blocks
+        .iter()
+        .map(|b| {
+            let mut v = [0.0; DIMS];
+            v[..CHROMA].copy_from_slice(&b.chroma);
+            v[CHROMA..CHROMA + TIMBRE].copy_from_slice(&b.timbre);
+            v[DIMS - 1] = b.loudness;
+            v
+        })
+        .collect::<Vec<_>>();
+
+    let n = vectors.len() as f32;
+    for d in 0..DIMS {
+        let weight = if d < CHROMA {
+            c.chroma_weight / (CHROMA as f32).sqrt()
+        } else if d < CHROMA + TIMBRE {
+            c.timbre_weight / (TIMBRE as f32).sqrt()
+        } else {
+            c.loudness_weight
+        };
+        let mean = vectors.iter().map(|v| v[d]).sum::<f32>() / n;
+        let var = vectors.iter().map(|v| (v[d] - mean).powi(2)).sum::<f32>() / n;
+        let std = var.sqrt().max(1e-6);
+        vectors
+            .iter_mut()
+            .for_each(|v| v[d] = (v[d] - mean) / std * weight);
+    }
+    vectors
+}
+
+fn cosine(a: &[f32; DIMS], b: &[f32; DIMS]) -> f32 {
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let norm = |v: &[f32; DIMS]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    dot / (norm(a) * norm(b)).max(f32::EPSILON)
+}
+
+/// Correlation of the self-similarity matrix with a gaussian tapered
+/// checkerboard kernel reaching `half` blocks away from the diagonal
+fn novelty(vectors: &[[f32; DIMS]], half: usize) -> Vec<f32> {
+    let n = vectors.len() as isize;
+    let half = half.max(1) as isize;
+    let sigma = half as f32 / 2.0;
+    let taper = |x: isize| f32::exp(-0.5 * (x as f32 + 0.5).powi(2) / (sigma * sigma));
+
+    (0..n)
+        .map(|i| {
+            let mut sum = 0.0;
+            for a in -half..half {
+                for b in -half..half {
+                    let (x, y) = (i + a, i + b);
+                    if x < 0 || y < 0 || x >= n || y >= n {
+                        continue;
+                    }
+                    // positive within the same side of `i`, negative across
+                    let sign = if (a < 0) == (b < 0) { 1.0 } else { -1.0 };
+                    let s = cosine(&vectors[x as usize], &vectors[y as usize]);
+                    sum += sign * taper(a) * taper(b) * s;
+                }
+            }
+            sum.max(0.0)
+        })
+        .collect()
+}
+
+/// Local maxima at least `min_distance` apart that are more than `threshold`
+/// standard deviations above the mean
+fn peaks(novelty: &[f32], threshold: f32, min_distance: usize) -> Vec<usize> {
+    let n = novelty.len() as f32;
+    let mean = novelty.iter().sum::<f32>() / n;
+    let std = (novelty.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / n).sqrt();
+    let min_distance = min_distance.max(1);
+
+    let mut peaks: Vec<usize> = Vec::new();
+    for (i, &x) in novelty.iter().enumerate() {
+        if x <= mean + threshold * std || i < min_distance || i + min_distance > novelty.len() {
+            continue;
+        }
+        let start = i.saturating_sub(min_distance);
+        let end = (i + min_distance + 1).min(novelty.len());
+        let is_max = novelty[start..end]
+            .iter()
+            .enumerate()
+            .all(|(j, &y)| y < x || (y == x && start + j >= i));
+        if is_max && peaks.last().is_none_or(|&p| i - p >= min_distance) {
+            peaks.push(i);
+        }
+    }
+    peaks
+}
+
+/// Gives every segment the label of the first earlier segment it's similar
+/// enough to, or a new one
+fn labels(means: &[[f32; DIMS]], similarity: f32) -> Vec<usize> {
+    let mut first: Vec<usize> = Vec::new();
+    means
+        .iter()
+        .map(|mean| {
+            let label = first
+                .iter()
+                .position(|&j| cosine(&means[j], mean) >= similarity);
+            label.unwrap_or_else(|| {
+                first.push(first.len());
+                first.len() - 1
+            })
+        })
+        .collect()
+}
+
+fn label_name(label: usize) -> String {
+    let letter = (b'A' + (label % 26) as u8) as char;
+    match label / 26 {
+        0 => letter.to_string(),
+        n => format!("{letter}{n}"),
+    }
+}
+
+fn kinds(cfg: &AnalysisConfig, labels: &[usize], energy: &[f32]) -> Vec<SegmentKind> {
+    let c = &cfg.segment;
+    let count = labels.iter().max().map_or(0, |&l| l + 1);
+    let label_energy = (0..count)
+        .map(|l| {
+            let e = labels.iter().zip(energy).filter(|(x, _)| **x == l);
+            let n = e.clone().count();
+            (n, e.map(|(_, e)| e).sum::<f32>() / n.max(1) as f32)
+        })
+        .collect::<Vec<_>>();
+    // the loudest label that comes back is the chorus
+    let chorus = label_energy
+        .iter()
+        .enumerate()
+        .filter(|(_, (n, _))| *n >= 2)
+        .max_by(|a, b| a.1.1.total_cmp(&b.1.1))
+        .map(|(l, _)| l);
+
+    let last = labels.len() - 1;
+    (0..labels.len())
+        .map(|i| {
+            let jump = i > 0 && energy[i] >= energy[i - 1] * c.drop_jump;
+            if i == 0 && energy[i] < c.quiet {
+                SegmentKind::Intro
+            } else if i == last && i > 0 && energy[i] < c.quiet {
+                SegmentKind::Outro
+            } else if jump && energy[i] >= 1.0 - c.quiet {
+                SegmentKind::Drop
+            } else if Some(labels[i]) == chorus {
+                SegmentKind::Chorus
+            } else {
+                SegmentKind::Verse
+            }
+        })
+        .collect()
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct SegmentConfig {
+    /// Seconds of hops averaged into one block of the self-similarity matrix
+    pub resolution: f32,
+    /// Width of the checkerboard kernel in seconds
+    pub kernel: f32,
+    /// Standard deviations above the mean novelty a boundary has to reach
+    pub threshold: f32,
+    /// Shortest possible segment in seconds
+    pub min_length: f32,
+    pub chroma_weight: f32,
+    pub timbre_weight: f32,
+    pub loudness_weight: f32,
+    /// Cosine similarity above which two segments get the same label
+    pub label_similarity: f32,
+    /// Relative energy below which the first and last segments are an intro
+    /// and outro
+    pub quiet: f32,
+    /// How many times louder than the segment before a drop is
+    pub drop_jump: f32,
+}
+
+impl Default for SegmentConfig {
+    fn default() -> Self {
+        Self {
+            resolution: 0.5,
+            kernel: 16.0,
+            threshold: 0.5,
+            min_length: 8.0,
+            chroma_weight: 1.0,
+            timbre_weight: 1.0,
+            loudness_weight: 1.0,
+            label_similarity: 0.5,
+            quiet: 0.3,
+            drop_jump: 2.0,
+        }
+    }
+}
+
+#[test]
+fn test_sections() {
+    let cfg = AnalysisConfig::default();
+    let mut chroma = [0.0; CHROMA];
+    // quiet C major, loud noisy A major, and both again
+    let sections = [
+        (3, 10.0, -30.0),
+        (0, 40.0, -8.0),
+        (3, 10.0, -30.0),
+        (0, 40.0, -8.0),
+    ];
+    let hops = (20.0 / cfg.hop_duration()) as usize;
+
+    let mut segmenter = Segmenter::new(&cfg);
+    for (root, noise, loudness) in sections {
+        chroma.fill(0.0);
+        for n in [0, 4, 7].map(|n| (root + n) % 12) {
+            chroma[n] = 1.0 / 3.0;
+        }
+        for h in 0..hops {
+            // a bit of wobble so nothing is perfectly constant
+            let wobble = (h as f32 * 0.37).sin() * 0.05;
+            segmenter.features.push(Features {
+                chroma,
+                timbre: [
+                    100.0 * noise,
+                    50.0 * noise,
+                    200.0 * noise,
+                    noise / 100.0,
+                    noise / 200.0,
+                ]
+                .map(|x| x * (1.0 + wobble)),
+                loudness: loudness + wobble,
+            });
+        }
+    }
+
+    let segments = segmenter.finish(&cfg).segments;
+    let starts = segments.iter().map(|s| s.start).collect::<Vec<_>>();
+    assert_eq!(segments.len(), 4, "{segments:?}");
+    for (start, expected) in starts.iter().zip([0.0, 20.0, 40.0, 60.0]) {
+        assert!((start - expected).abs() < 1.5, "{starts:?}");
+    }
+    let labels = segments
+        .iter()
+        .map(|s| s.label.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(labels, ["A", "B", "A", "B"]);
+    assert_eq!(segments[0].kind, SegmentKind::Intro);
+    assert!(segments[1].energy > 0.9 && segments[0].energy < 0.1);
+    assert!(toml::from_str::<Segments>(&toml::to_string(&Segments { segments }).unwrap()).is_ok());
+}
@@ -12,19 +12,40 @@ use crate::{
     util::{profile_function, vec_clone, vec_default},
 };
 
+pub mod beat;
+pub mod channel;
+pub mod cqt;
+pub mod drums;
 pub mod fft;
+pub mod harmony;
 pub mod hps;
 pub mod light;
 pub mod loudness;
+pub mod mel;
+pub mod melody;
+pub mod onset;
 pub mod paint;
 pub mod power;
+pub mod spectral;
 
 #[derive(Clone)]
 pub struct AnalysisState {
-    pub buffer: VecDeque<i16>,
+    /// Number of hops analyzed so far
+    pub hop: usize,
+    /// Downmixed samples of the current frame
+    pub buffer: VecDeque<f32>,
+    pub channels: channel::ChannelData,
     pub fft: fft::FftData,
+    pub cqt: cqt::CqtData,
+    pub mel: mel::MelData,
+    pub spectral: spectral::SpectralData,
     pub hps: hps::HpsData,
+    pub onset: onset::OnsetData,
+    pub beat: beat::BeatData,
+    pub drums: drums::DrumsData,
     pub power: power::PowerData,
+    pub harmony: harmony::HarmonyData,
+    pub melody: melody::MelodyData,
     pub light: light::LightData,
     pub loudness: loudness::LoudnessData,
     pub paint: paint::PaintData,
@@ -32,14 +53,24 @@ pub struct AnalysisState {
 }
 
 impl AnalysisState {
-    pub fn blank(cfg: &AnalysisConfig) -> Self {
+    pub fn blank(cfg: &AnalysisConfig, channels: usize) -> Self {
         Self {
-            buffer: VecDeque::from_iter(iter::repeat_n(0, cfg.fft.frame_len)),
+            hop: 0,
+            buffer: VecDeque::from_iter(iter::repeat_n(0.0, cfg.fft.frame_len)),
+            channels: channel::ChannelData::blank(cfg, channels),
             fft: fft::FftData::blank(cfg),
+            cqt: cqt::CqtData::blank(cfg),
+            mel: mel::MelData::blank(cfg),
+            spectral: spectral::SpectralData::blank(cfg),
             hps: hps::HpsData::blank(cfg),
+            onset: onset::OnsetData::blank(cfg),
+            beat: beat::BeatData::blank(cfg),
+            drums: drums::DrumsData::blank(cfg),
             power: power::PowerData::blank(cfg),
+            harmony: harmony::HarmonyData::blank(cfg),
+            melody: melody::MelodyData::blank(cfg),
             light: light::LightData::blank(cfg),
-            loudness: loudness::LoudnessData::default(),
+            loudness: loudness::LoudnessData::blank(cfg, channels),
             paint: paint::PaintData::blank(cfg),
             easing: fs::read_to_string("easing.toml")
                 .ok()
@@ -48,40 +79,96 @@ impl AnalysisState {
         }
     }
 
+    /// `hop_samples` are `hop_len` frames of interleaved samples, with as many
+    /// channels as the state was created with.
     pub fn from_prev(
         cfg: &AnalysisConfig,
         mut prev: AnalysisState,
-        hop_samples: impl ExactSizeIterator<Item = i16>,
+        hop_samples: &[f32],
         ebur: &mut EbuR128,
     ) -> Self {
         profile_function!();
+        debug_assert_eq!(hop_samples.len(), cfg.fft.hop_len * prev.channels.count);
 
+        let mut loudness = prev.loudness.advance(cfg, hop_samples, ebur);
+        let hop_samples = loudness.normalize(cfg, hop_samples.to_vec());
         prev.buffer.drain(0..cfg.fft.hop_len);
         prev.buffer
-            .extend(cfg.loudness.normalize(hop_samples, ebur));
-        let loudness = cfg.loudness.data(ebur);
+            .extend(channel::downmix(&hop_samples, prev.channels.count));
 
         FieldsIterMut::new(&mut prev.easing)
             .filter_map(|(_, f)| f.downcast_mut::<EasingFunction>())
-            .for_each(|f| f.last_x.clear());
-        let fft = fft::FftData::new(prev.fft.fft.clone(), cfg, prev.buffer.iter().cloned());
-        let hps = prev.hps.advance(cfg, &fft);
-        let paint = prev
-            .paint
-            .advance(&cfg.paint, &mut prev.easing, &prev.light, &prev.power);
-        let power = power::PowerData::new(cfg, &hps, prev.power);
+            .for_each(|f| f.next_hop());
+        let channels = prev.channels.advance(cfg, &hop_samples);
+        let fft = prev.fft.advance(cfg, prev.buffer.iter().cloned());
+        let cqt = prev.cqt.advance(
+            cfg,
+            prev.buffer
+                .range(cfg.fft.frame_len - cfg.fft.hop_len..)
+                .cloned(),
+        );
+        let mel = prev.mel.advance(&fft);
+        let spectral = prev
+            .spectral
+            .advance(cfg, &fft, prev.buffer.iter().cloned());
+        let hps = prev.hps.advance(cfg, &fft, prev.buffer.iter().cloned());
+        let hop = prev.hop + 1;
+        let onset = prev.onset.advance(cfg, &hps, hop);
+        let beat = prev.beat.advance(cfg, &onset);
+        let drums = prev.drums.advance(cfg, &hps, hop);
+        let paint = prev.paint.advance(
+            &cfg.paint,
+            &mut prev.easing,
+            &prev.light,
+            &prev.power,
+            &loudness,
+        );
+        let power = power::PowerData::new(cfg, &fft, &hps, &cqt, &channels, prev.power);
+        let harmony = prev.harmony.advance(cfg, &power);
+        let melody = prev.melody.advance(cfg, &hps);
         let light = prev.light.advance(cfg, &power);
         Self {
+            hop,
             buffer: prev.buffer,
+            channels,
             hps,
+            onset,
+            beat,
+            drums,
             fft,
+            cqt,
+            mel,
+            spectral,
             power,
+            harmony,
+            melody,
             light,
             loudness,
             paint,
             easing: prev.easing,
         }
     }
+
+    /// Redoes the stages that build on `hps` after it was replaced, carrying
+    /// their state over from `prev`. `paint` is left as it was.
+    pub(crate) fn rederive(mut self, cfg: &AnalysisConfig, prev: &AnalysisState) -> Self {
+        profile_function!();
+        self.onset = prev.onset.clone().advance(cfg, &self.hps, self.hop);
+        self.beat = prev.beat.clone().advance(cfg, &self.onset);
+        self.drums = prev.drums.clone().advance(cfg, &self.hps, self.hop);
+        self.power = power::PowerData::new(
+            cfg,
+            &self.fft,
+            &self.hps,
+            &self.cqt,
+            &self.channels,
+            prev.power.clone(),
+        );
+        self.harmony = prev.harmony.clone().advance(cfg, &self.power);
+        self.melody = prev.melody.clone().advance(cfg, &self.hps);
+        self.light = prev.light.clone().advance(cfg, &self.power);
+        self
+    }
 }
 
 /// Raw spectrogram data right out of &Fft
@@ -90,12 +177,12 @@ pub struct RawSpec<T>(pub Vec<T>);
 
 impl<T: Default> RawSpec<T> {
     pub fn blank_default(cfg: &AnalysisConfig) -> Self {
-        Self(vec_default(cfg.fft.frame_len))
+        Self(vec_default(cfg.raw_len()))
     }
 }
 impl<T: Clone> RawSpec<T> {
     pub fn blank_clone(elem: &T, cfg: &AnalysisConfig) -> Self {
-        Self(vec_clone(elem, cfg.fft.frame_len))
+        Self(vec_clone(elem, cfg.raw_len()))
     }
 }
 
@@ -142,6 +229,10 @@ impl<T> AudibleSpec<T> {
             f(i, t);
         }
     }
+
+    pub fn into_inner(self) -> Vec<T> {
+        self.0
+    }
 }
 
 impl AudibleSpec<Complex<f32>> {
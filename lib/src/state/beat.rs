@@ -0,0 +1,184 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cfg::AnalysisConfig,
+    util::{RingBuffer, profile_function},
+};
+
+use super::onset::OnsetData;
+
+/// Causal beat tracker fed by the onset novelty curve.
+///
+/// The tempo comes from the autocorrelation of the recent novelty, weighted
+/// by a prior around `BeatConfig::prior_bpm`. The beat phase is a free
+/// running accumulator that gets pulled towards the best fitting comb of
+/// novelty peaks every hop.
+#[derive(Clone)]
+pub struct BeatData {
+    pub bpm: f32,
+    /// Position within the current beat, 0 right on the beat and going up
+    /// to 1
+    pub phase: f32,
+    /// How periodic the novelty is, from 0 to 1
+    pub confidence: f32,
+    /// Whether a beat happened during this hop
+    pub beat: bool,
+    /// Length of a beat in hops
+    period: f32,
+    novelty: RingBuffer<f32>,
+}
+
+impl BeatData {
+    pub fn blank(cfg: &AnalysisConfig) -> Self {
+        let len = (cfg.beat.window / cfg.hop_duration()) as usize;
+        Self {
+            bpm: cfg.beat.prior_bpm,
+            phase: 0.0,
+            confidence: 0.0,
+            beat: false,
+            period: bpm_to_period(cfg, cfg.beat.prior_bpm),
+            novelty: RingBuffer::from_default(len.max(1)),
+        }
+    }
+
+    pub fn advance(mut self, cfg: &AnalysisConfig, onset: &OnsetData) -> Self {
+        profile_function!();
+        let c = &cfg.beat;
+        self.novelty.replace(onset.flux);
+
+        let mean = self.novelty.iter().sum::<f32>() / self.novelty.len() as f32;
+        let novelty = self.novelty.iter().map(|n| n - mean).collect::<Vec<_>>();
+        let autocorrelation = |lag: usize| {
+            novelty
+                .iter()
+                .zip(&novelty[lag..])
+                .map(|(a, b)| a * b)
+                .sum::<f32>()
+        };
+
+        // tempo
+        let min_lag = (bpm_to_period(cfg, c.max_bpm).floor() as usize).max(1);
+        let max_lag = (bpm_to_period(cfg, c.min_bpm).ceil() as usize).min(novelty.len() / 2);
+        let energy = autocorrelation(0);
+        if energy > 0.0 && min_lag + 2 <= max_lag {
+            let prior_period = bpm_to_period(cfg, c.prior_bpm);
+            let ac = (min_lag - 1..=max_lag + 1)
+                .map(|lag| {
+                    let octaves = (lag as f32 / prior_period).log2() / c.prior_width;
+                    autocorrelation(lag) * f32::exp(-0.5 * octaves * octaves)
+                })
+                .collect::<Vec<_>>();
+            let (i, &peak) = ac[1..ac.len() - 1]
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(b.1))
+                .unwrap();
+            // parabolic interpolation around the peak
+            let (l, m, r) = (ac[i], ac[i + 1], ac[i + 2]);
+            let curvature = l - 2.0 * m + r;
+            let offset = if curvature < 0.0 {
+                0.5 * (l - r) / curvature
+            } else {
+                0.0
+            };
+
+            let period = (i + min_lag) as f32 + offset;
+            let s = c.tempo_smoothing;
+            self.period = self.period * s + period * (1.0 - s);
+            self.confidence = (peak / energy).clamp(0.0, 1.0);
+        }
+        self.bpm = 60.0 / (self.period * cfg.hop_duration());
+
+        // phase
+        self.phase += 1.0 / self.period;
+        self.beat = self.phase >= 1.0;
+        self.phase = self.phase.fract();
+
+        let period = self.period.round() as usize;
+        if period > 0 && period < novelty.len() {
+            let comb = |offset: usize| {
+                (0..)
+                    .map(|k| offset as f32 + k as f32 * self.period)
+                    .map(|i| i.round() as usize)
+                    .take_while(|&i| i < novelty.len())
+                    .map(|i| novelty[novelty.len() - 1 - i])
+                    .sum::<f32>()
+            };
+            let (since_beat, _) = (0..period)
+                .map(|offset| (offset, comb(offset)))
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .unwrap();
+            let target = since_beat as f32 / self.period;
+            let error = (target - self.phase + 0.5).rem_euclid(1.0) - 0.5;
+            self.phase = (self.phase + error * c.phase_gain * self.confidence).rem_euclid(1.0);
+        }
+        self
+    }
+}
+
+fn bpm_to_period(cfg: &AnalysisConfig, bpm: f32) -> f32 {
+    60.0 / bpm / cfg.hop_duration()
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct BeatConfig {
+    /// Seconds of novelty to look at
+    pub window: f32,
+    pub min_bpm: f32,
+    pub max_bpm: f32,
+    /// Center of the tempo prior
+    pub prior_bpm: f32,
+    /// Standard deviation of the tempo prior in octaves
+    pub prior_width: f32,
+    /// How much of the previous tempo estimate to keep every hop
+    pub tempo_smoothing: f32,
+    /// How strongly the beat phase follows the novelty
+    pub phase_gain: f32,
+}
+
+impl Default for BeatConfig {
+    fn default() -> Self {
+        Self {
+            window: 6.0,
+            min_bpm: 60.0,
+            max_bpm: 200.0,
+            prior_bpm: 120.0,
+            prior_width: 1.0,
+            tempo_smoothing: 0.9,
+            phase_gain: 0.2,
+        }
+    }
+}
+
+#[test]
+fn test_click_tempo() {
+    use crate::Analyzer;
+
+    let mut cfg = AnalysisConfig::default();
+    cfg.loudness.normalize = false;
+    let sample_rate = cfg.fft.sample_rate;
+
+    for bpm in [90.0, 128.0] {
+        let beat_len = (60.0 / bpm * sample_rate as f32) as usize;
+        let mut samples = vec![0.0; sample_rate * 10];
+        for start in (0..samples.len() - 64).step_by(beat_len) {
+            for (i, s) in samples[start..start + 64].iter_mut().enumerate() {
+                *s = if i % 2 == 0 { 0.8 } else { -0.8 };
+            }
+        }
+
+        let mut analyzer = Analyzer::new(cfg.clone(), 1, sample_rate as u32);
+        analyzer.push(&samples);
+        let mut beats = 0;
+        while let Some(state) = analyzer.next_frame() {
+            beats += state.beat.beat as usize;
+        }
+
+        let beat = &analyzer.state().beat;
+        assert!((beat.bpm - bpm).abs() < 2.0, "{} != {bpm}", beat.bpm);
+        assert!(beat.confidence > 0.3, "{}", beat.confidence);
+        let expected = 10.0 * bpm / 60.0;
+        assert!((beats as f32 - expected).abs() < 3.0, "{beats} beats");
+    }
+}
@@ -0,0 +1,52 @@
+use std::{collections::VecDeque, iter};
+
+use crate::{cfg::AnalysisConfig, util::profile_function};
+
+use super::fft::FftData;
+
+/// Per-channel spectrograms, only filled in when `FftConfig::per_channel` is
+/// set
+#[derive(Clone)]
+pub struct ChannelData {
+    /// Number of interleaved channels in the input
+    pub count: usize,
+    pub buffers: Vec<VecDeque<f32>>,
+    pub fft: Vec<FftData>,
+}
+
+impl ChannelData {
+    pub fn blank(cfg: &AnalysisConfig, count: usize) -> Self {
+        assert!(count > 0);
+        let n = if cfg.fft.per_channel { count } else { 0 };
+        Self {
+            count,
+            buffers: Vec::from_iter(
+                (0..n).map(|_| VecDeque::from_iter(iter::repeat_n(0.0, cfg.fft.frame_len))),
+            ),
+            fft: Vec::from_iter((0..n).map(|_| FftData::blank(cfg))),
+        }
+    }
+
+    /// `hop` is a hop worth of interleaved samples
+    pub fn advance(mut self, cfg: &AnalysisConfig, hop: &[f32]) -> Self {
+        profile_function!();
+        for (c, buffer) in self.buffers.iter_mut().enumerate() {
+            buffer.drain(0..cfg.fft.hop_len);
+            buffer.extend(hop.iter().skip(c).step_by(self.count));
+        }
+        self.fft = self
+            .fft
+            .into_iter()
+            .zip(&self.buffers)
+            .map(|(fft, buffer)| fft.advance(cfg, buffer.iter().cloned()))
+            .collect();
+        self
+    }
+}
+
+/// Averages interleaved samples into a single channel
+pub fn downmix(samples: &[f32], channels: usize) -> impl ExactSizeIterator<Item = f32> {
+    samples
+        .chunks_exact(channels)
+        .map(move |frame| frame.iter().sum::<f32>() / channels as f32)
+}
@@ -0,0 +1,232 @@
+use std::{collections::VecDeque, f32::consts::PI, iter, sync::Arc};
+
+use realfft::{RealFftPlanner, RealToComplex};
+use rustfft::{FftPlanner, num_complex::Complex};
+use serde::{Deserialize, Serialize};
+
+use crate::{cfg::AnalysisConfig, unit::Power, util::profile_function};
+
+/// Sparse spectral kernel, already conjugated and divided by the frame length
+type Kernel = Vec<(usize, Complex<f32>)>;
+
+/// How many times its power a steady sine leaves in a semitone of bins. Each
+/// kernel is a Hann window of `n` samples normalized to unit sum, and the bins
+/// are spaced `1 / n` cycles per sample apart, so summed over neighbouring
+/// bins the responses add up to `n * Σw² / (Σw)²`, which is 1.5 for Hann.
+/// Bins cut short by `CqtConfig::frame_len` overlap more than this.
+const KERNEL_OVERLAP: f32 = 1.5;
+
+/// Constant-Q transform using the spectral kernels of Brown and Puckette
+/// (1992), with bins centered on the notes from `NotesConfig::lowest_note` up.
+/// Only filled in when `CqtConfig::enabled` is set.
+#[derive(Clone)]
+pub struct CqtData {
+    /// `bins_per_semitone` bins for every semitone
+    pub bins: Vec<Complex<f32>>,
+    pub power: Vec<Power>,
+    kernels: Arc<[Kernel]>,
+    /// Factor from a semitone of bins to [`super::AudibleSpec::power`]
+    scale: f32,
+    fft: Arc<dyn RealToComplex<f32>>,
+    buffer: VecDeque<f32>,
+    input: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+}
+
+impl CqtData {
+    pub fn blank(cfg: &AnalysisConfig) -> Self {
+        let c = &cfg.cqt;
+        let len = if c.enabled { c.frame_len } else { 0 };
+        let fft = RealFftPlanner::new().plan_fft_forward(len);
+        let kernels = if c.enabled {
+            kernels(cfg)
+        } else {
+            Arc::new([])
+        };
+        // a sine of amplitude `a` has a power of `a² / 4` in its kernel and
+        // `a² / 4 * Σw²` on the positive side of the analysis window's spectrum
+        let window = cfg.fft.window.coefficients(cfg.fft.frame_len);
+        let scale = window.iter().map(|w| w * w).sum::<f32>() / KERNEL_OVERLAP;
+        Self {
+            bins: vec![Complex::ZERO; kernels.len()],
+            power: vec![Power(0.0); kernels.len()],
+            kernels,
+            scale,
+            // holds at least a hop, even if the frame is shorter
+            buffer: VecDeque::from_iter(iter::repeat_n(0.0, len.max(cfg.fft.hop_len))),
+            input: fft.make_input_vec(),
+            spectrum: fft.make_output_vec(),
+            scratch: fft.make_scratch_vec(),
+            fft,
+        }
+    }
+
+    /// `hop` is the newest hop of downmixed samples
+    pub fn advance(mut self, cfg: &AnalysisConfig, hop: impl Iterator<Item = f32>) -> Self {
+        if !cfg.cqt.enabled {
+            return self;
+        }
+        profile_function!();
+
+        self.buffer.drain(0..cfg.fft.hop_len);
+        self.buffer.extend(hop);
+        let start = self.buffer.len() - self.input.len();
+        for (x, s) in self.input.iter_mut().zip(self.buffer.range(start..)) {
+            *x = *s;
+        }
+        self.fft
+            .process_with_scratch(&mut self.input, &mut self.spectrum, &mut self.scratch)
+            .unwrap();
+
+        for ((bin, power), kernel) in self
+            .bins
+            .iter_mut()
+            .zip(&mut self.power)
+            .zip(self.kernels.iter())
+        {
+            *bin = kernel.iter().map(|&(j, k)| self.spectrum[j] * k).sum();
+            *power = Power(bin.norm_sqr());
+        }
+        self
+    }
+
+    /// Power of the `i`th semitone above the lowest note, scaled to match
+    /// [`super::AudibleSpec::power`] for a steady sine. The bins are shifted by `tuning` cents,
+    /// rounded to the nearest bin.
+    pub fn semitone_power(&self, cfg: &AnalysisConfig, i: usize, tuning: f32) -> f32 {
+        let b = cfg.cqt.bins_per_semitone;
+        let shift = (tuning / 100.0 * b as f32).round() as isize;
+        let start = (i * b).saturating_add_signed(shift).min(self.power.len());
+        let end = (start + b).min(self.power.len());
+        let power = self.power[start..end].iter().map(|p| **p).sum::<f32>();
+        power * self.scale
+    }
+}
+
+/// Center frequency of bin `k`
+pub fn frequency(cfg: &AnalysisConfig, k: usize) -> f32 {
+    let b = cfg.cqt.bins_per_semitone as f32;
+    let offset = (b - 1.0) / 2.0;
+    cfg.notes.lowest_hz() as f32 * 2f32.powf((k as f32 - offset) / (12.0 * b))
+}
+
+fn kernels(cfg: &AnalysisConfig) -> Arc<[Kernel]> {
+    let c = &cfg.cqt;
+    let len = c.frame_len;
+    let sr = cfg.fft.sample_rate as f32;
+    let q = 1.0 / (2f32.powf(1.0 / (12.0 * c.bins_per_semitone as f32)) - 1.0);
+    let fft = FftPlanner::new().plan_fft_forward(len);
+
+    (0..12 * cfg.notes.octaves * c.bins_per_semitone)
+        .map(|k| {
+            let f = frequency(cfg, k);
+            // lower bins get cut short by the frame, which makes them variable-Q
+            let n = usize::min((q * sr / f).ceil() as usize, len);
+            let offset = len - n;
+            let window = (0..n).map(|i| 0.5 - 0.5 * f32::cos(2.0 * PI * i as f32 / n as f32));
+            let w_sum = window.clone().sum::<f32>();
+
+            let mut kernel = vec![Complex::ZERO; len];
+            for (i, w) in window.enumerate() {
+                let phase = 2.0 * PI * f * (offset + i) as f32 / sr;
+                kernel[offset + i] = Complex::from_polar(w / w_sum, phase);
+            }
+            fft.process(&mut kernel);
+
+            let max = kernel.iter().map(|k| k.norm()).fold(0.0, f32::max);
+            kernel[..=len / 2]
+                .iter()
+                .enumerate()
+                .filter(|(_, k)| k.norm() >= max * c.sparsity)
+                .map(|(j, k)| (j, k.conj() / len as f32))
+                .collect()
+        })
+        .collect()
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct CqtConfig {
+    /// Read note power from the constant-Q transform instead of the FFT
+    pub enabled: bool,
+    pub bins_per_semitone: usize,
+    /// Samples of history the transform looks at, which caps the length of
+    /// the lowest kernels
+    pub frame_len: usize,
+    /// Kernel values below this fraction of their peak are dropped
+    pub sparsity: f32,
+}
+
+impl Default for CqtConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bins_per_semitone: 3,
+            frame_len: 16384,
+            sparsity: 0.005,
+        }
+    }
+}
+
+#[test]
+fn test_bass_notes() {
+    use crate::{Analyzer, state::power::PowerData};
+    use std::f32::consts::TAU;
+
+    let mut cfg = AnalysisConfig::default();
+    cfg.loudness.normalize = false;
+    cfg.cqt.enabled = true;
+    let power = |cfg: &AnalysisConfig, note: usize| -> PowerData {
+        let f = cfg.notes.lowest_hz() as f32 * 2f32.powf(note as f32 / 12.0);
+        let samples = (0..cfg.cqt.frame_len + cfg.fft.frame_len)
+            .map(|i| f32::sin(i as f32 / cfg.fft.sample_rate as f32 * f * TAU) * 0.25)
+            .collect::<Vec<_>>();
+
+        let mut analyzer = Analyzer::new(cfg.clone(), 1, cfg.fft.sample_rate as u32);
+        analyzer.push(&samples);
+        while analyzer.next_frame().is_some() {}
+        analyzer.state().power.clone()
+    };
+
+    for note in [0, 1, 2, 5, 11] {
+        let octave_power = power(&cfg, note).octave_power;
+        let (max, _) = octave_power
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .unwrap();
+        assert_eq!(max, note, "{octave_power:?}");
+    }
+
+    // high enough for the FFT to resolve semitones
+    let fft_cfg = AnalysisConfig {
+        cqt: CqtConfig {
+            enabled: false,
+            ..cfg.cqt.clone()
+        },
+        ..cfg.clone()
+    };
+    for note in [24, 31, 40, 47] {
+        let (octave, pitch_class) = (note / 12, cfg.notes.pitch_class(note));
+        let cqt = power(&cfg, note).note_power[octave][pitch_class];
+        let fft = power(&fft_cfg, note).note_power[octave][pitch_class];
+        assert!(
+            cqt < fft * 1.25 && fft < cqt * 1.25,
+            "{note}: {cqt} vs {fft}"
+        );
+    }
+}
+
+#[test]
+fn test_short_frame() {
+    use crate::Analyzer;
+
+    let mut cfg = AnalysisConfig::default();
+    cfg.cqt.enabled = true;
+    cfg.cqt.frame_len = cfg.fft.hop_len / 2;
+    let mut analyzer = Analyzer::new(cfg.clone(), 1, cfg.fft.sample_rate as u32);
+    analyzer.push(&vec![0.1f32; cfg.fft.hop_len * 4]);
+    while analyzer.next_frame().is_some() {}
+    assert_eq!(analyzer.state().hop, 4);
+}
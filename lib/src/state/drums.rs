@@ -0,0 +1,294 @@
+use std::{ops::Range, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{cfg::AnalysisConfig, util::profile_function};
+
+use super::{
+    hps::HpsData,
+    onset::{Onset, PeakConfig, PeakPicker},
+};
+
+/// Kick, snare and hi-hat activations of the percussive spectrum.
+///
+/// The spectrum is summed into fractional octave bands, so narrow low drums
+/// and wide high ones weigh about the same, and decomposed into fixed
+/// templates with a few multiplicative NMF updates of the activations every
+/// hop, warm started from the last hop. Onsets are peaks in the rise of each
+/// activation.
+#[derive(Clone)]
+pub struct DrumsData {
+    pub kick: Drum,
+    pub snare: Drum,
+    pub hihat: Drum,
+    /// Range of audible bins in every band
+    bands: Arc<[Range<usize>]>,
+    /// Normalized templates of the kick, snare and hi-hat over the bands
+    templates: Arc<[Vec<f32>; 3]>,
+}
+
+#[derive(Clone)]
+pub struct Drum {
+    /// How much of the template is in the spectrum
+    pub activation: f32,
+    /// Log-compressed activation that jumps up and decays slowly
+    pub envelope: f32,
+    /// Onset found in this hop, one hop late like `OnsetData::onset`
+    pub onset: Option<Onset>,
+    level: f32,
+    picker: PeakPicker,
+}
+
+impl Drum {
+    fn blank(cfg: &AnalysisConfig) -> Self {
+        Self {
+            activation: 0.0,
+            envelope: 0.0,
+            onset: None,
+            level: 0.0,
+            picker: PeakPicker::new(&cfg.drums.peak),
+        }
+    }
+
+    fn advance(&mut self, cfg: &AnalysisConfig, activation: f32, hop: usize) {
+        let c = &cfg.drums;
+        self.activation = activation;
+        let level = f32::ln_1p(c.compression * activation);
+        let rise = f32::max(0.0, level - self.level);
+        self.level = level;
+        self.envelope = f32::max(level, self.envelope * c.decay);
+
+        let peak = self.picker.consume(&c.peak, cfg.hop_duration(), rise);
+        self.onset = peak.map(|strength| Onset {
+            time: hop.saturating_sub(1) as f32 * cfg.hop_duration(),
+            strength,
+        });
+    }
+}
+
+impl DrumsData {
+    pub fn blank(cfg: &AnalysisConfig) -> Self {
+        let c = &cfg.drums;
+        let (bands, centers) = bands(cfg);
+        Self {
+            kick: Drum::blank(cfg),
+            snare: Drum::blank(cfg),
+            hihat: Drum::blank(cfg),
+            bands,
+            templates: Arc::new([&c.kick, &c.snare, &c.hihat].map(|t| t.template(&centers))),
+        }
+    }
+
+    /// `hop` is the index of the hop `hps` was computed from
+    pub fn advance(mut self, cfg: &AnalysisConfig, hps: &HpsData, hop: usize) -> Self {
+        profile_function!();
+        let c = &cfg.drums;
+        let v = self
+            .bands
+            .iter()
+            .map(|r| hps.percussive[r.clone()].iter().map(|x| x.norm_sqr()).sum())
+            .collect::<Vec<f32>>();
+        let w = &*self.templates;
+
+        // Euclidean NMF updates with the templates held fixed:
+        // h <- h * (W^T v) / (W^T W h)
+        let wtv = w.each_ref().map(|w| dot(w, &v));
+        let mut gram = [[0.0; 3]; 3];
+        for (i, row) in gram.iter_mut().enumerate() {
+            for (j, g) in row.iter_mut().enumerate() {
+                *g = dot(&w[i], &w[j]);
+            }
+        }
+        let mut h = [&self.kick, &self.snare, &self.hihat].map(|d| d.activation.max(1e-6));
+        for _ in 0..c.iterations {
+            let wtwh = gram.map(|row| row.iter().zip(&h).map(|(g, h)| g * h).sum::<f32>());
+            for k in 0..3 {
+                h[k] *= wtv[k] / wtwh[k].max(f32::MIN_POSITIVE);
+            }
+        }
+
+        let scale = 1.0 / cfg.fft.frame_len as f32;
+        self.kick.advance(cfg, h[0] * scale, hop);
+        self.snare.advance(cfg, h[1] * scale, hop);
+        self.hihat.advance(cfg, h[2] * scale, hop);
+        self
+    }
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+/// Fractional octave bands over the audible range and their center
+/// frequencies, skipping bands that don't contain a bin
+fn bands(cfg: &AnalysisConfig) -> (Arc<[Range<usize>]>, Vec<f32>) {
+    let step = 2f32.powf(1.0 / cfg.drums.bands_per_octave as f32);
+    let max = cfg.idx_to_hz(cfg.max_idx());
+    let mut lo = cfg.idx_to_hz(cfg.min_idx()).max(1.0);
+    let (mut bands, mut centers) = (Vec::new(), Vec::new());
+    while lo < max {
+        let hi = lo * step;
+        let start = cfg.hz_to_idx(lo).max(cfg.min_idx()) - cfg.min_idx();
+        let end = cfg.hz_to_idx(hi).min(cfg.max_idx()) - cfg.min_idx();
+        if end > start {
+            bands.push(start..end);
+            centers.push((lo * hi).sqrt());
+        }
+        lo = hi;
+    }
+    (bands.into(), centers)
+}
+
+/// Gaussian bump on a log frequency axis
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Bump {
+    pub frequency: f32,
+    /// Standard deviation in octaves
+    pub width: f32,
+    pub weight: f32,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct DrumTemplate {
+    pub bumps: Vec<Bump>,
+}
+
+impl DrumTemplate {
+    fn template(&self, centers: &[f32]) -> Vec<f32> {
+        let mut template = centers
+            .iter()
+            .map(|&f| {
+                self.bumps
+                    .iter()
+                    .map(|b| {
+                        let octaves = f32::log2(f / b.frequency) / b.width;
+                        b.weight * f32::exp(-0.5 * octaves * octaves)
+                    })
+                    .sum::<f32>()
+            })
+            .collect::<Vec<_>>();
+        let norm = template.iter().map(|x| x * x).sum::<f32>().sqrt();
+        template
+            .iter_mut()
+            .for_each(|x| *x /= norm.max(f32::EPSILON));
+        template
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct DrumsConfig {
+    pub kick: DrumTemplate,
+    pub snare: DrumTemplate,
+    pub hihat: DrumTemplate,
+    pub bands_per_octave: usize,
+    /// NMF updates per hop
+    pub iterations: usize,
+    /// Activations are compressed with `ln(1 + compression * x)`
+    pub compression: f32,
+    /// How much of the envelope is left after every hop
+    pub decay: f32,
+    pub peak: PeakConfig,
+}
+
+impl Default for DrumsConfig {
+    fn default() -> Self {
+        let bump = |frequency, width, weight| Bump {
+            frequency,
+            width,
+            weight,
+        };
+        Self {
+            kick: DrumTemplate {
+                bumps: vec![bump(60.0, 0.6, 1.0)],
+            },
+            snare: DrumTemplate {
+                bumps: vec![bump(200.0, 0.5, 0.6), bump(3000.0, 1.2, 0.4)],
+            },
+            hihat: DrumTemplate {
+                bumps: vec![bump(7000.0, 0.5, 1.0)],
+            },
+            bands_per_octave: 3,
+            iterations: 10,
+            compression: 100.0,
+            decay: 0.85,
+            // leakage between the templates makes small rises meaningless
+            peak: PeakConfig {
+                delta: 1.0,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[test]
+fn test_drum_hits() {
+    use crate::Analyzer;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+    use std::f32::consts::TAU;
+
+    let mut cfg = AnalysisConfig::default();
+    cfg.loudness.normalize = false;
+    let sample_rate = cfg.fft.sample_rate;
+    let t = |i: usize| i as f32 / sample_rate as f32;
+    // band limited noise from a bunch of sines with random phases, seeded so
+    // every run hears the same hits
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut noise = |lo: f32, hi: f32, len: usize| {
+        let partials = (0..64)
+            .map(|_| (rng.random_range(lo..hi), rng.random_range(0.0..TAU)))
+            .collect::<Vec<_>>();
+        (0..len)
+            .map(|i| {
+                partials
+                    .iter()
+                    .map(|(f, p)| f32::sin(t(i) * f * TAU + p))
+                    .sum::<f32>()
+                    / 8.0
+            })
+            .collect::<Vec<_>>()
+    };
+    let kick = (0..sample_rate / 8)
+        .map(|i| f32::sin(t(i) * 55.0 * TAU) * f32::exp(-t(i) * 20.0) * 0.8)
+        .collect::<Vec<_>>();
+    let snare = noise(1000.0, 5000.0, sample_rate / 8)
+        .iter()
+        .enumerate()
+        .map(|(i, n)| (f32::sin(t(i) * 200.0 * TAU) + n) * 0.3 * f32::exp(-t(i) * 30.0))
+        .collect::<Vec<_>>();
+    let hihat = noise(6000.0, 8000.0, sample_rate / 16)
+        .iter()
+        .enumerate()
+        .map(|(i, n)| n * 0.3 * f32::exp(-t(i) * 60.0))
+        .collect::<Vec<_>>();
+
+    let hits = [(0.5, &kick), (1.0, &hihat), (1.5, &snare), (2.0, &kick)];
+    let mut samples = vec![0.0; sample_rate * 3];
+    for (time, hit) in hits {
+        let start = (time * sample_rate as f32) as usize;
+        samples[start..start + hit.len()].copy_from_slice(hit);
+    }
+
+    let mut analyzer = Analyzer::new(cfg.clone(), 1, sample_rate as u32);
+    analyzer.push(&samples);
+    let mut onsets = [vec![], vec![], vec![]];
+    while let Some(state) = analyzer.next_frame() {
+        let d = &state.drums;
+        for (onsets, drum) in onsets.iter_mut().zip([&d.kick, &d.snare, &d.hihat]) {
+            onsets.extend(drum.onset.map(|o| o.time));
+        }
+    }
+
+    let frame = cfg.fft.frame_len as f32 / sample_rate as f32;
+    let [kicks, snares, hihats] = onsets;
+    let near = |onsets: &[f32], times: &[f32]| {
+        onsets.len() == times.len()
+            && onsets
+                .iter()
+                .zip(times)
+                .all(|(o, t)| *o >= t - frame && *o <= t + frame)
+    };
+    assert!(near(&kicks, &[0.5, 2.0]), "{kicks:?}");
+    assert!(near(&snares, &[1.5]), "{snares:?}");
+    assert!(near(&hihats, &[1.0]), "{hihats:?}");
+}
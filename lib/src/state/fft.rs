@@ -1,4 +1,4 @@
-use rustfft::{Fft, FftPlanner, num_complex::Complex};
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex, num_complex::Complex};
 use serde::{Deserialize, Serialize};
 use std::{collections::VecDeque, f32::consts::PI, iter, sync::Arc};
 
@@ -8,165 +8,215 @@ use super::{AudibleSpec, RawSpec};
 
 #[derive(Clone)]
 pub struct FftData {
+    /// The non-negative frequencies, `frame_len / 2 + 1` bins
     pub raw: RawSpec<Complex<f32>>,
     pub audible: AudibleSpec<Complex<f32>>,
     pub power: AudibleSpec<unit::Power>,
     pub db: AudibleSpec<unit::Db>,
-    pub fft: Arc<dyn Fft<f32>>,
+    pub fft: Arc<dyn RealToComplex<f32>>,
+    pub window: Arc<[f32]>,
+    input: Vec<f32>,
+    scratch: Vec<Complex<f32>>,
 }
 
 impl FftData {
     pub fn blank(cfg: &AnalysisConfig) -> Self {
+        let fft = RealFftPlanner::new().plan_fft_forward(cfg.fft.frame_len);
         Self {
             raw: RawSpec::blank_default(cfg),
             audible: AudibleSpec::blank_default(cfg),
             power: AudibleSpec::blank_default(cfg),
             db: AudibleSpec::blank_default(cfg),
-            fft: FftPlanner::new().plan_fft_forward(cfg.fft.frame_len),
+            window: cfg.fft.window.coefficients(cfg.fft.frame_len),
+            input: fft.make_input_vec(),
+            scratch: fft.make_scratch_vec(),
+            fft,
         }
     }
 
-    pub fn new(
-        fft: Arc<dyn Fft<f32>>,
+    /// Reuses the buffers of the previous frame, so this doesn't allocate
+    pub fn advance(
+        mut self,
         cfg: &AnalysisConfig,
-        samples: impl ExactSizeIterator<Item = i16>,
+        samples: impl ExactSizeIterator<Item = f32>,
     ) -> Self {
         profile_function!();
-        let raw = RawSpec(fft_samples(fft.as_ref(), samples));
-        let audible = raw.audible_slice(cfg).into_iter().cloned().collect();
-        let db = raw
-            .audible_slice(cfg)
-            .into_iter()
-            .map(|a| a.norm().into())
-            .collect();
-        let magnitude = raw
-            .audible_slice(cfg)
-            .into_iter()
-            .map(|a| unit::Power(a.norm_sqr()))
-            .collect();
+        assert_eq!(self.input.len(), samples.len());
+        for ((x, sample), w) in self.input.iter_mut().zip(samples).zip(self.window.iter()) {
+            *x = sample * w;
+        }
+        self.fft
+            .process_with_scratch(&mut self.input, &mut self.raw, &mut self.scratch)
+            .unwrap();
 
-        Self {
-            raw,
-            audible: AudibleSpec(audible),
-            power: AudibleSpec(magnitude),
-            db: AudibleSpec(db),
-            fft,
+        let raw = self.raw.audible_slice(cfg);
+        self.audible.0.copy_from_slice(raw);
+        for ((a, power), db) in raw.iter().zip(&mut self.power.0).zip(&mut self.db.0) {
+            *power = unit::Power(a.norm_sqr());
+            *db = a.norm().into();
         }
+        self
     }
 }
 
-// fn hamming_window_multiplier(i: usize, len: usize) -> f32 {
-//     0.54 - (0.46 * (2.0 * PI * i as f32 / f32::cos(len as f32 - 1.0)))
-// }
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Window {
+    Hann,
+    Hamming,
+    /// 4-term Blackman-Harris
+    BlackmanHarris,
+    Kaiser {
+        beta: f32,
+    },
+    FlatTop,
+}
+
+impl Window {
+    pub fn value(self, i: usize, len: usize) -> f32 {
+        debug_assert!(i < len);
+        // every window is 1 in the middle, which a single sample is
+        if len == 1 {
+            return 1.0;
+        }
+        let x = 2.0 * PI * i as f32 / (len - 1) as f32;
+        let cosine_sum = |a: &[f32]| {
+            a.iter()
+                .enumerate()
+                .map(|(k, a)| a * f32::cos(k as f32 * x) * if k % 2 == 0 { 1.0 } else { -1.0 })
+                .sum()
+        };
+        match self {
+            Window::Hann => cosine_sum(&[0.5, 0.5]),
+            Window::Hamming => cosine_sum(&[0.54, 0.46]),
+            Window::BlackmanHarris => cosine_sum(&[0.35875, 0.48829, 0.14128, 0.01168]),
+            Window::Kaiser { beta } => {
+                let r = 2.0 * i as f32 / (len - 1) as f32 - 1.0;
+                bessel_i0(beta * f32::sqrt(1.0 - r * r)) / bessel_i0(beta)
+            }
+            Window::FlatTop => {
+                cosine_sum(&[0.21557895, 0.41663158, 0.27726316, 0.083578947, 0.006947368])
+            }
+        }
+    }
+
+    pub fn coefficients(self, len: usize) -> Arc<[f32]> {
+        (0..len).map(|i| self.value(i, len)).collect()
+    }
+}
 
-fn hann_window(i: usize, len: usize) -> f32 {
-    debug_assert!(i < len);
-    0.5 * (1.0 - f32::cos(2.0 * PI * i as f32 / (len - 1) as f32))
+/// Zeroth order modified bessel function of the first kind
+fn bessel_i0(x: f32) -> f32 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    for k in 1..50 {
+        term *= (x / (2.0 * k as f32)).powi(2);
+        sum += term;
+        if term < sum * 1e-8 {
+            break;
+        }
+    }
+    sum
 }
 
-/// Runs a discrete fourier transform on a buffer of audio samples
-pub fn fft_samples(
-    fft: &dyn Fft<f32>,
-    samples: impl ExactSizeIterator<Item = i16>,
-) -> Vec<Complex<f32>> {
-    assert_eq!(fft.len(), samples.len());
+/// Reference for [`FftData::advance`] with a plain complex FFT, returning the
+/// `len / 2 + 1` non-negative frequencies
+#[cfg(test)]
+fn fft_samples(window: &[f32], samples: impl ExactSizeIterator<Item = f32>) -> Vec<Complex<f32>> {
+    assert_eq!(window.len(), samples.len());
+    let len = window.len();
     let mut buffer = samples
-        .into_iter()
-        .map(|i| (i as f32) / i16::MAX as f32)
-        .enumerate()
-        .map(|(i, sample)| sample * hann_window(i, fft.len()))
-        .map(|re| Complex { re, im: 0.0 })
+        .zip(window)
+        .map(|(sample, w)| Complex::new(sample * w, 0.0))
         .collect::<Vec<_>>();
-    fft.process(&mut buffer);
+    rustfft::FftPlanner::new()
+        .plan_fft_forward(len)
+        .process(&mut buffer);
+    buffer.truncate(len / 2 + 1);
     buffer
 }
 
+/// Weighted overlap-add inverse of [`FftData::advance`], using the analysis
+/// window for synthesis as well
 pub struct InverseStft {
-    fft: Arc<dyn Fft<f32>>,
+    fft: Arc<dyn ComplexToReal<f32>>,
+    window: Arc<[f32]>,
     hop_len: usize,
     samples: VecDeque<Vec<f32>>,
-    // buffer: VecDeque<Complex<f32>>,
+    /// `1 / (N * sum(w^2))` for every sample of a hop
+    norm: Vec<f32>,
+    input: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
 }
 
 impl InverseStft {
     pub fn new(cfg: &AnalysisConfig) -> Self {
+        let len = cfg.fft.frame_len;
+        let hop_len = cfg.fft.hop_len;
+        assert_eq!(len % hop_len, 0, "frame_len must be a multiple of hop_len");
+
+        let window = cfg.fft.window.coefficients(len);
+        let norm = (0..hop_len)
+            .map(|r| {
+                let w_sum = (r..len).step_by(hop_len).map(|i| window[i] * window[i]);
+                1.0 / (len as f32 * w_sum.sum::<f32>()).max(f32::EPSILON)
+            })
+            .collect();
+        let fft = RealFftPlanner::new().plan_fft_inverse(len);
         Self {
-            fft: FftPlanner::new().plan_fft_inverse(cfg.fft.frame_len),
-            hop_len: cfg.fft.hop_len,
-            samples: VecDeque::from_iter(iter::repeat_n(vec![0.0; cfg.fft.frame_len], cfg.hops())),
-            // buffer: VecDeque::from_iter(iter::repeat_n(Complex::ZERO, cfg.fft.frame_len)),
+            window,
+            hop_len,
+            samples: VecDeque::from_iter(iter::repeat_n(vec![0.0; len], cfg.hops())),
+            norm,
+            input: fft.make_input_vec(),
+            scratch: fft.make_scratch_vec(),
+            fft,
         }
     }
 
-    /// Returns the samples for the frequencies given `hop_num - 1` frames ago
+    /// Returns the samples for the frequencies given `hop_num - 1` frames ago.
+    ///
+    /// `frequencies` are the `frame_len / 2 + 1` non-negative frequencies, like
+    /// [`FftData::raw`].
     #[must_use]
-    pub fn push(
-        &mut self,
-        mut frequencies: Vec<Complex<f32>>,
-    ) -> impl ExactSizeIterator<Item = i16> {
-        assert_eq!(self.fft.len(), frequencies.len());
-
-        // self.buffer.drain(0..self.hop_len);
-        // self.buffer.extend(frequencies.into_iter());
-
-        // let mut buffer = self.buffer.iter().cloned().collect::<Vec<_>>();
-        self.fft.process(&mut frequencies);
-        self.samples.pop_front();
-        self.samples
-            .push_back(frequencies.into_iter().map(|c| c.re).collect());
-
-        let hops = self.fft.len() / self.hop_len;
-        (0..self.hop_len)
-            .into_iter()
-            .map(move |r| {
-                let mut sum = 0.0;
-                let mut w_sum = 0.0;
-                for n in 0..hops {
-                    let i = (hops - n - 1) * self.hop_len + r;
-                    let w = hann_window(i, self.fft.len());
-                    sum += self.samples[n][i] * w;
-                    w_sum += w * w;
-                }
-                sum / w_sum
-            })
-            .map(|f| (f * 20.0) as i16)
-        // .map(|f| (f * i16::MAX as f32) as i16)
+    pub fn push(&mut self, frequencies: &[Complex<f32>]) -> impl ExactSizeIterator<Item = f32> {
+        self.input.copy_from_slice(frequencies);
+        // the imaginary parts of DC and nyquist have to be zero for a real output
+        self.input[0].im = 0.0;
+        if let Some(last) = self.input.last_mut()
+            && self.fft.len() % 2 == 0
+        {
+            last.im = 0.0;
+        }
+
+        let mut frame = self.samples.pop_front().unwrap();
+        self.fft
+            .process_with_scratch(&mut self.input, &mut frame, &mut self.scratch)
+            .unwrap();
+        self.samples.push_back(frame);
+
+        let hops = self.samples.len();
+        (0..self.hop_len).map(move |r| {
+            let mut sum = 0.0;
+            for n in 0..hops {
+                let i = (hops - n - 1) * self.hop_len + r;
+                sum += self.samples[n][i] * self.window[i];
+            }
+            sum * self.norm[r]
+        })
     }
 }
 
-// pub fn istft_samples(
-//     fft: &dyn Fft<f32>,
-//     mut frequencies: Vec<Complex<f32>>,
-//     hop_len: usize,
-// ) -> impl Iterator<Item = i16> {
-//     assert_eq!(fft.len(), frequencies.len());
-
-//     fft.process(&mut frequencies);
-
-//     let hops = fft.len() / hop_len;
-//     (0..hop_len)
-//         .into_iter()
-//         .rev()
-//         .map(move |r| {
-//             let mut sum = 0.0;
-//             let mut w_sum = 0.0;
-//             for n in 1..=hops {
-//                 let i = n * hop_len - r - 1;
-//                 let w = hann_window(i, fft.len());
-//                 sum += frequencies[i].re * w / fft.len() as f32;
-//                 w_sum += w * w;
-//             }
-//             sum / w_sum
-//         })
-//         .map(|f| (f * i16::MAX as f32) as i16)
-// }
-
 #[derive(Deserialize, Serialize, Clone)]
 #[serde(default)]
 pub struct FftConfig {
     pub frame_len: usize,
     pub hop_len: usize,
     pub sample_rate: usize,
+    pub window: Window,
+    /// Also keep a spectrogram for every input channel
+    pub per_channel: bool,
 }
 
 impl Default for FftConfig {
@@ -175,6 +225,99 @@ impl Default for FftConfig {
             frame_len: 4096,
             hop_len: 1024,
             sample_rate: 44100,
+            window: Window::Hann,
+            per_channel: false,
+        }
+    }
+}
+
+#[test]
+fn test_windows() {
+    let windows = [
+        Window::Hann,
+        Window::Hamming,
+        Window::BlackmanHarris,
+        Window::Kaiser { beta: 8.0 },
+        Window::FlatTop,
+    ];
+    let len = 1025;
+    for window in windows {
+        let w = window.coefficients(len);
+        for i in 0..len / 2 {
+            assert!((w[i] - w[len - 1 - i]).abs() < 1e-5, "{window:?} {i}");
+        }
+        assert!((w[len / 2] - 1.0).abs() < 1e-5, "{window:?}");
+        assert_eq!(&*window.coefficients(1), &[1.0], "{window:?}");
+    }
+
+    let endpoint = |window: Window| window.value(0, len);
+    assert!(endpoint(Window::Hann).abs() < 1e-6);
+    assert!((endpoint(Window::Hamming) - 0.08).abs() < 1e-6);
+    assert!(endpoint(Window::BlackmanHarris) < 1e-4);
+    let kaiser = endpoint(Window::Kaiser { beta: 8.0 });
+    assert!((kaiser - 1.0 / bessel_i0(8.0)).abs() < 1e-6, "{kaiser}");
+
+    // coherent gain is the mean of the window
+    let gain = |window: Window| window.coefficients(len).iter().sum::<f32>() / len as f32;
+    assert!((gain(Window::Hann) - 0.5).abs() < 1e-3);
+    assert!((gain(Window::FlatTop) - 0.21557895).abs() < 1e-3);
+}
+
+#[test]
+fn test_matches_complex_fft() {
+    let mut cfg = AnalysisConfig::default();
+    cfg.fft.window = Window::BlackmanHarris;
+    let samples = (0..cfg.fft.frame_len)
+        .map(|i| f32::sin(i as f32 * 0.05) + 0.5 * f32::cos(i as f32 * 0.31))
+        .collect::<Vec<_>>();
+
+    let fft = FftData::blank(&cfg).advance(&cfg, samples.iter().cloned());
+    let expected = fft_samples(&fft.window, samples.iter().cloned());
+    assert_eq!(fft.raw.len(), expected.len());
+    for (i, (a, b)) in fft.raw.iter().zip(&expected).enumerate() {
+        assert!((a - b).norm() < 1e-3 * b.norm().max(1.0), "{i}: {a} != {b}");
+    }
+}
+
+#[test]
+fn test_istft_round_trip() {
+    let windows = [
+        Window::Hann,
+        Window::Hamming,
+        Window::BlackmanHarris,
+        Window::Kaiser { beta: 8.0 },
+        Window::FlatTop,
+    ];
+    for window in windows {
+        for ratio in [2, 4, 8] {
+            let mut cfg = AnalysisConfig::default();
+            cfg.fft.window = window;
+            cfg.fft.hop_len = cfg.fft.frame_len / ratio;
+            let (len, hop_len) = (cfg.fft.frame_len, cfg.fft.hop_len);
+
+            let coefficients = window.coefficients(len);
+            let mut istft = InverseStft::new(&cfg);
+
+            let signal = (0..len * 4)
+                .map(|i| i as f32)
+                .map(|i| 0.5 * f32::sin(i * 0.05) + 0.3 * f32::sin(i * 0.731) * f32::cos(i * 0.002))
+                .collect::<Vec<_>>();
+            let mut buffer = VecDeque::from_iter(iter::repeat_n(0.0, len));
+            let mut out = Vec::new();
+            for hop in signal.chunks(hop_len) {
+                buffer.drain(0..hop_len);
+                buffer.extend(hop);
+                let spec = fft_samples(&coefficients, buffer.iter().cloned());
+                out.extend(istft.push(&spec));
+            }
+
+            // the output lags behind by everything but the newest hop
+            for (i, (a, b)) in out[len - hop_len..].iter().zip(&signal).enumerate() {
+                assert!(
+                    (a - b).abs() < 1e-3,
+                    "{window:?} 1/{ratio} @ {i}: {a} != {b}"
+                );
+            }
         }
     }
 }
@@ -0,0 +1,242 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{cfg::AnalysisConfig, util::profile_function};
+
+use super::power::PowerData;
+
+/// Names of the pitch classes, in the same order as `PowerData::octave_power`
+pub const PITCH_CLASSES: [&str; 12] = [
+    "A", "A#", "B", "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#",
+];
+
+/// Krumhansl-Kessler key profiles, starting at the tonic
+const MAJOR_PROFILE: [f32; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f32; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Major,
+    Minor,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Key {
+    /// Pitch class of the tonic, 0 is A
+    pub tonic: usize,
+    pub mode: Mode,
+}
+
+impl Key {
+    pub fn name(&self) -> String {
+        let mode = match self.mode {
+            Mode::Major => "major",
+            Mode::Minor => "minor",
+        };
+        format!("{} {mode}", PITCH_CLASSES[self.tonic])
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Triad {
+    Major,
+    Minor,
+    Diminished,
+    Augmented,
+}
+
+impl Triad {
+    const ALL: [Triad; 4] = [
+        Triad::Major,
+        Triad::Minor,
+        Triad::Diminished,
+        Triad::Augmented,
+    ];
+
+    /// Semitones of the third and fifth above the root
+    const fn intervals(self) -> [usize; 2] {
+        match self {
+            Triad::Major => [4, 7],
+            Triad::Minor => [3, 7],
+            Triad::Diminished => [3, 6],
+            Triad::Augmented => [4, 8],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Chord {
+    /// Pitch class of the root, 0 is A
+    pub root: usize,
+    pub triad: Triad,
+}
+
+impl Chord {
+    pub fn name(&self) -> String {
+        let suffix = match self.triad {
+            Triad::Major => "",
+            Triad::Minor => "m",
+            Triad::Diminished => "dim",
+            Triad::Augmented => "aug",
+        };
+        format!("{}{suffix}", PITCH_CLASSES[self.root])
+    }
+}
+
+#[derive(Clone)]
+pub struct HarmonyData {
+    /// Normalized chroma with the chord smoothing applied
+    pub chroma: [f32; 12],
+    pub key: Key,
+    /// Correlation of the smoothed chroma with the key profile
+    pub key_confidence: f32,
+    /// `None` when it's too quiet or nothing matches well enough
+    pub chord: Option<Chord>,
+    /// Cosine similarity of `chroma` with the chord template
+    pub chord_confidence: f32,
+    key_chroma: [f32; 12],
+}
+
+impl HarmonyData {
+    pub fn blank(_cfg: &AnalysisConfig) -> Self {
+        Self {
+            chroma: [0.0; 12],
+            key: Key {
+                tonic: 3,
+                mode: Mode::Major,
+            },
+            key_confidence: 0.0,
+            chord: None,
+            chord_confidence: 0.0,
+            key_chroma: [0.0; 12],
+        }
+    }
+
+    pub fn advance(mut self, cfg: &AnalysisConfig, power: &PowerData) -> Self {
+        profile_function!();
+        let c = &cfg.harmony;
+        let total = power.octave_power.iter().sum::<f32>();
+        if total < c.min_power {
+            self.chord = None;
+            self.chord_confidence = 0.0;
+            return self;
+        }
+
+        for i in 0..12 {
+            let x = power.octave_power[i] / total;
+            self.chroma[i] = self.chroma[i] * c.chord_smoothing + x * (1.0 - c.chord_smoothing);
+            self.key_chroma[i] = self.key_chroma[i] * c.key_smoothing + x * (1.0 - c.key_smoothing);
+        }
+
+        let keys = (0..12).flat_map(|tonic| {
+            [(Mode::Major, MAJOR_PROFILE), (Mode::Minor, MINOR_PROFILE)]
+                .map(|(mode, profile)| (Key { tonic, mode }, profile))
+        });
+        (self.key, self.key_confidence) = keys
+            .map(|(key, profile)| {
+                let rotated = |i: usize| profile[(i + 12 - key.tonic) % 12];
+                (key, correlation(&self.key_chroma, rotated))
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+
+        let norm = self.chroma.iter().map(|x| x * x).sum::<f32>().sqrt();
+        let (chord, similarity) = (0..12)
+            .flat_map(|root| Triad::ALL.map(|triad| Chord { root, triad }))
+            .map(|chord| {
+                let [third, fifth] = chord.triad.intervals();
+                let notes = [0, third, fifth].map(|i| self.chroma[(chord.root + i) % 12]);
+                let similarity = notes.iter().sum::<f32>() / (norm * f32::sqrt(3.0));
+                (chord, similarity)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        self.chord = (similarity >= c.min_chord_similarity).then_some(chord);
+        self.chord_confidence = similarity;
+        self
+    }
+}
+
+/// Pearson correlation between the chroma and a profile
+fn correlation(chroma: &[f32; 12], profile: impl Fn(usize) -> f32) -> f32 {
+    let mean_x = chroma.iter().sum::<f32>() / 12.0;
+    let mean_y = (0..12).map(&profile).sum::<f32>() / 12.0;
+    let (mut xy, mut xx, mut yy) = (0.0, 0.0, 0.0);
+    for (i, x) in chroma.iter().enumerate() {
+        let (x, y) = (x - mean_x, profile(i) - mean_y);
+        xy += x * y;
+        xx += x * x;
+        yy += y * y;
+    }
+    xy / f32::sqrt(xx * yy).max(f32::EPSILON)
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct HarmonyConfig {
+    /// How much of the previous chroma to keep every hop for the key
+    pub key_smoothing: f32,
+    /// How much of the previous chroma to keep every hop for chords
+    pub chord_smoothing: f32,
+    /// Chords matching worse than this are reported as `None`
+    pub min_chord_similarity: f32,
+    /// Total `octave_power` below which the harmony is left alone
+    pub min_power: f32,
+}
+
+impl Default for HarmonyConfig {
+    fn default() -> Self {
+        Self {
+            key_smoothing: 0.995,
+            chord_smoothing: 0.7,
+            min_chord_similarity: 0.6,
+            min_power: 1e-3,
+        }
+    }
+}
+
+#[test]
+fn test_cadence() {
+    use crate::Analyzer;
+    use std::f32::consts::TAU;
+
+    let mut cfg = AnalysisConfig::default();
+    cfg.loudness.normalize = false;
+    // the FFT bins are too coarse to tell apart neighbouring notes this low
+    cfg.cqt.enabled = true;
+    cfg.harmony.key_smoothing = 0.95;
+    let sample_rate = cfg.fft.sample_rate;
+    // frequency of a pitch class in the octave above A3
+    let hz = |pc: usize| 220.0 * 2f32.powf(pc as f32 / 12.0);
+    let chord = |notes: [usize; 3]| {
+        (0..sample_rate).map(move |i| {
+            let t = i as f32 / sample_rate as f32;
+            notes
+                .iter()
+                .map(|&n| f32::sin(t * hz(n) * TAU))
+                .sum::<f32>()
+                * 0.1
+        })
+    };
+
+    // C F G C
+    let progression = [[3, 7, 10], [8, 0, 3], [10, 2, 5], [3, 7, 10]];
+    let mut analyzer = Analyzer::new(cfg.clone(), 1, sample_rate as u32);
+    for notes in progression {
+        analyzer.push(&chord(notes).collect::<Vec<_>>());
+        while analyzer.next_frame().is_some() {}
+
+        let harmony = &analyzer.state().harmony;
+        let expected = Chord {
+            root: notes[0],
+            triad: Triad::Major,
+        };
+        assert_eq!(harmony.chord, Some(expected), "{}", expected.name());
+    }
+
+    let key = analyzer.state().harmony.key;
+    assert_eq!(key.name(), "C major");
+}
@@ -1,10 +1,13 @@
+use std::sync::Arc;
+
+use realfft::{RealFftPlanner, RealToComplex};
 use rustfft::num_complex::Complex;
 use serde::{Deserialize, Serialize};
 
 use crate::{
     cfg::AnalysisConfig,
     unit::Power,
-    util::{profile_function, profile_scope},
+    util::{profile_function, profile_scope, vec_clone},
 };
 
 use super::{AudibleSpec, fft::FftData};
@@ -19,6 +22,12 @@ pub struct HpsData {
     pub residual: AudibleSpec<Complex<f32>>,
     pub h_filtered: AudibleSpec<Power>,
     pub p_filtered: AudibleSpec<Power>,
+    /// Part of `percussive` and `residual` that the short frame pass finds
+    /// harmonic, zero unless `VocalConfig::enabled`
+    pub vocal: AudibleSpec<Complex<f32>>,
+    /// Smoothed share of the power in the vocal range that is `vocal`
+    pub vocal_activity: f32,
+    vocal_pass: VocalPass,
 }
 
 impl HpsData {
@@ -26,11 +35,7 @@ impl HpsData {
         let a_len = cfg.max_aidx();
         Self {
             past_magnitudes: AudibleSpec::from_iter(
-                (0..a_len)
-                    .into_iter()
-                    .map(|i| i as f32 / a_len as f32)
-                    .map(|f| f + 0.5)
-                    .map(|f| median::Filter::new((f * cfg.hps.h_filter_span as f32) as usize)),
+                (0..a_len).map(|i| median::Filter::new(h_filter_len(cfg, i))),
                 cfg,
             ),
             h_enhanced: AudibleSpec::blank_default(cfg),
@@ -40,10 +45,19 @@ impl HpsData {
             residual: AudibleSpec::blank_default(cfg),
             h_filtered: AudibleSpec::blank_default(cfg),
             p_filtered: AudibleSpec::blank_default(cfg),
+            vocal: AudibleSpec::blank_default(cfg),
+            vocal_activity: 0.0,
+            vocal_pass: VocalPass::new(cfg),
         }
     }
 
-    pub fn advance(mut self, cfg: &AnalysisConfig, fft: &FftData) -> Self {
+    /// `frame` are the downmixed samples `fft` was computed from
+    pub fn advance(
+        mut self,
+        cfg: &AnalysisConfig,
+        fft: &FftData,
+        frame: impl ExactSizeIterator<Item = f32>,
+    ) -> Self {
         profile_function!();
         let hps = &cfg.hps;
 
@@ -73,6 +87,20 @@ impl HpsData {
             );
         }
 
+        if hps.vocal.enabled {
+            profile_scope!("vocal pass");
+            self.vocal_pass.advance(cfg, frame);
+        }
+        let vocal_activity = self.vocal_activity;
+        self.separate(cfg, fft, vocal_activity);
+        self
+    }
+
+    /// Splits `fft` into its parts with the masks from `h_enhanced` and
+    /// `p_enhanced`. `vocal_activity` is the one of the hop before.
+    pub(crate) fn separate(&mut self, cfg: &AnalysisConfig, fft: &FftData, vocal_activity: f32) {
+        let hps = &cfg.hps;
+
         struct Mask {
             mask_h: f32,
             mask_p: f32,
@@ -112,7 +140,109 @@ impl HpsData {
             self.p_filtered.update(|i, _| self.p_enhanced[i] * masks[i].mask_p);
         }
 
-        self
+        if hps.vocal.enabled {
+            profile_scope!("vocal");
+            let v = &hps.vocal;
+
+            // the short pass has fewer bins, so interpolate its mask
+            let mask = &self.vocal_pass.mask;
+            let scale = self.vocal_pass.input.len() as f32 / cfg.fft.frame_len as f32;
+            self.vocal.update(|i, _| {
+                let x = (i + cfg.min_idx()) as f32 * scale;
+                let k = (x as usize).min(mask.len() - 2);
+                let t = x - k as f32;
+                let mask = mask[k] * (1.0 - t) + mask[k + 1] * t;
+                (self.percussive[i] + self.residual[i]) * mask
+            });
+
+            let aidx =
+                |hz: f32| cfg.hz_to_idx(hz).clamp(cfg.min_idx(), cfg.max_idx()) - cfg.min_idx();
+            let range = aidx(v.min_frequency)..aidx(v.max_frequency);
+            let vocal = self.vocal[range.clone()]
+                .iter()
+                .map(|a| a.norm_sqr())
+                .sum::<f32>();
+            let total = fft.power[range].iter().map(|p| **p).sum::<f32>();
+            let share = if total > 0.0 { vocal / total } else { 0.0 };
+            self.vocal_activity = vocal_activity * v.smoothing + share * (1.0 - v.smoothing);
+        }
+    }
+}
+
+/// Hops in the time median of bin `i`, which gets longer with frequency
+pub fn h_filter_len(cfg: &AnalysisConfig, i: usize) -> usize {
+    let f = i as f32 / cfg.max_aidx() as f32 + 0.5;
+    (f * cfg.hps.h_filter_span as f32) as usize
+}
+
+/// Second HPS pass over the input with a short frame.
+///
+/// With a long frame, sung notes wobble too much to look harmonic and end up
+/// in the percussive and residual parts. With a short frame they look steady
+/// again while drums still look percussive, so the harmonic mask of this pass
+/// applied to the percussive and residual parts of the long pass picks out
+/// the vocals.
+#[derive(Clone)]
+struct VocalPass {
+    fft: Arc<dyn RealToComplex<f32>>,
+    window: Arc<[f32]>,
+    input: Vec<f32>,
+    output: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    past_power: Vec<median::Filter<Power>>,
+    /// Harmonic mask of every bin of the short frame
+    mask: Vec<f32>,
+}
+
+impl VocalPass {
+    fn new(cfg: &AnalysisConfig) -> Self {
+        let v = &cfg.hps.vocal;
+        // at least two bins of output to interpolate between
+        let len = v.frame_len.min(cfg.fft.frame_len).max(4);
+        let fft = RealFftPlanner::new().plan_fft_forward(len);
+        let output = fft.make_output_vec();
+        Self {
+            window: cfg.fft.window.coefficients(len),
+            input: fft.make_input_vec(),
+            scratch: fft.make_scratch_vec(),
+            past_power: vec_clone(&median::Filter::new(v.h_filter_span), output.len()),
+            mask: vec![0.0; output.len()],
+            output,
+            fft,
+        }
+    }
+
+    /// Runs the pass on the short frame at the end of `frame`
+    fn advance(&mut self, cfg: &AnalysisConfig, frame: impl ExactSizeIterator<Item = f32>) {
+        let v = &cfg.hps.vocal;
+        let skip = frame.len().saturating_sub(self.input.len());
+        for ((x, sample), w) in self
+            .input
+            .iter_mut()
+            .zip(frame.skip(skip))
+            .zip(self.window.iter())
+        {
+            *x = sample * w;
+        }
+        self.fft
+            .process_with_scratch(&mut self.input, &mut self.output, &mut self.scratch)
+            .unwrap();
+
+        let mut filter = median::Filter::new(v.p_filter_span);
+        let e = f32::EPSILON;
+        for ((a, past), mask) in self
+            .output
+            .iter()
+            .zip(&mut self.past_power)
+            .zip(&mut self.mask)
+        {
+            let power = Power(a.norm_sqr());
+            past.consume(power);
+            filter.consume(power);
+            let h = *past.median();
+            let p = *filter.median();
+            *mask = ((h + e) / (h + p + e + e)).powf(v.factor);
+        }
     }
 }
 
@@ -123,6 +253,7 @@ pub struct HpsConfig {
     pub h_filter_span: usize,
     pub p_factor: f32,
     pub h_factor: f32,
+    pub vocal: VocalConfig,
 }
 
 impl Default for HpsConfig {
@@ -137,6 +268,91 @@ impl Default for HpsConfig {
             h_filter_span: 46,
             p_factor: 2.0,
             h_factor: 2.0,
+            vocal: Default::default(),
         }
     }
 }
+
+/// Short frame pass that pulls the vocals out of the percussive and residual
+/// parts
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct VocalConfig {
+    pub enabled: bool,
+    /// Clamped to between 4 and `FftConfig::frame_len`
+    pub frame_len: usize,
+    /// Hops in the time median of the short pass
+    pub h_filter_span: usize,
+    /// Bins in the frequency median of the short pass
+    pub p_filter_span: usize,
+    /// Exponent of the harmonic mask of the short pass
+    pub factor: f32,
+    /// Range `HpsData::vocal_activity` is measured over
+    pub min_frequency: f32,
+    pub max_frequency: f32,
+    /// How much of the vocal activity is kept every hop
+    pub smoothing: f32,
+}
+
+impl Default for VocalConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            frame_len: 1024,
+            // 44.1k samples/s * 0.2s / 1024 samples/hop
+            h_filter_span: 9,
+            // 9 * 44.1k / 1024 = ~390hz
+            p_filter_span: 9,
+            factor: 2.0,
+            min_frequency: 100.0,
+            max_frequency: 4000.0,
+            smoothing: 0.8,
+        }
+    }
+}
+
+#[test]
+fn test_vocal() {
+    use crate::Analyzer;
+    use std::f32::consts::TAU;
+
+    let mut cfg = AnalysisConfig::default();
+    cfg.loudness.normalize = false;
+    cfg.hps.vocal.enabled = true;
+    let sample_rate = cfg.fft.sample_rate;
+    // a note with a few harmonics that slides up and down by `slide`
+    // semitones every second
+    let activity = |cfg: &AnalysisConfig, slide: f32| {
+        let mut phase = 0.0;
+        let samples = (0..sample_rate * 2)
+            .map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                let f0 = 220.0 * 2f32.powf(slide / 12.0 * f32::sin(t * TAU));
+                phase += f0 / sample_rate as f32 * TAU;
+                (1..=5)
+                    .map(|h| f32::sin(phase * h as f32) / h as f32)
+                    .sum::<f32>()
+                    * 0.2
+            })
+            .collect::<Vec<_>>();
+        let mut analyzer = Analyzer::new(cfg.clone(), 1, sample_rate as u32);
+        analyzer.push(&samples);
+        while analyzer.next_frame().is_some() {}
+        analyzer.state().hps.vocal_activity
+    };
+
+    let steady = activity(&cfg, 0.0);
+    let sung = activity(&cfg, 3.0);
+    assert!(steady < 0.01, "{steady}");
+    assert!(sung > 10.0 * steady && sung > 0.03, "{sung} vs {steady}");
+
+    // longer than the frame it's cut from
+    cfg.hps.vocal.frame_len = cfg.fft.frame_len * 2;
+    assert!(activity(&cfg, 3.0) > 0.0);
+
+    // too short to have bins to interpolate between
+    for frame_len in [0, 1] {
+        cfg.hps.vocal.frame_len = frame_len;
+        assert!(activity(&cfg, 3.0).is_finite());
+    }
+}
@@ -1,4 +1,4 @@
-use std::array;
+use std::{array, iter};
 
 use serde::{Deserialize, Serialize};
 
@@ -7,7 +7,7 @@ use crate::{
     util::{RollingAverage, profile_function},
 };
 
-use super::power::{DData, PowerData};
+use super::power::PowerData;
 
 #[derive(Clone)]
 pub struct LightData {
@@ -31,8 +31,10 @@ impl LightData {
 
     pub fn advance(mut self, cfg: &AnalysisConfig, power: &PowerData) -> Self {
         profile_function!();
-        spiked_d_smooth(&mut self.p_raw, &power.p_filtered_power, &cfg.light);
-        spiked_d_smooth(&mut self.bp_raw, &power.p_bass_power, &cfg.light);
+        spiked_d_smooth(&mut self.p_raw, power.p_filtered_power.dval, &cfg.light);
+        if let Some(bass) = power.band(&cfg.light.bass_band) {
+            spiked_d_smooth(&mut self.bp_raw, bass.dval, &cfg.light);
+        }
         self.percussive.consume((self.p_raw + 1.0).log2());
         self.bass_percussive.consume((self.bp_raw + 1.0).log2());
         for (i, r) in self.notes.iter_mut().enumerate() {
@@ -40,10 +42,40 @@ impl LightData {
         }
         self
     }
+
+    /// Runs the smoothing of `p_raw` and `bp_raw` over them once more, from
+    /// the last of the states `ahead` of this one back to this one. Like a
+    /// forward-backward filter, peaks then rise before they hit about as
+    /// much as they fall after.
+    pub fn smooth_backward<'a>(
+        &mut self,
+        cfg: &AnalysisConfig,
+        ahead: impl DoubleEndedIterator<Item = &'a LightData>,
+    ) {
+        let raw = ahead
+            .rev()
+            .map(|l| (l.p_raw, l.bp_raw))
+            .chain(iter::once((self.p_raw, self.bp_raw)));
+        let (mut p, mut bp) = (self.p_raw, self.bp_raw);
+        let mut next = None;
+        for (l_p, l_bp) in raw {
+            match next {
+                Some((n_p, n_bp)) => {
+                    spiked_d_smooth(&mut p, l_p - n_p, &cfg.light);
+                    spiked_d_smooth(&mut bp, l_bp - n_bp, &cfg.light);
+                }
+                None => (p, bp) = (l_p, l_bp),
+            }
+            next = Some((l_p, l_bp));
+        }
+        (self.p_raw, self.bp_raw) = (p, bp);
+        self.percussive.fill((p + 1.0).log2());
+        self.bass_percussive.fill((bp + 1.0).log2());
+    }
 }
 
-fn spiked_d_smooth(l: &mut f32, d: &DData<f32>, cfg: &LightConfig) {
-    let mut dval = if d.dval > 0.0 { d.dval } else { d.dval * 0.5 };
+fn spiked_d_smooth(l: &mut f32, dval: f32, cfg: &LightConfig) {
+    let mut dval = if dval > 0.0 { dval } else { dval * 0.5 };
     if *l < 3.0 && dval < 0.0 {
         dval *= *l / 3.0;
     }
@@ -58,6 +90,10 @@ pub struct LightConfig {
     pub height: u32,
     pub decay: f32,
     pub gui_delay: u32,
+    /// Band from `AnalysisConfig::bands` that drives `bass_percussive`. It's
+    /// also taken out of `PowerData::p_filtered_power`. Checked by
+    /// `AnalysisConfig::validate`.
+    pub bass_band: String,
 }
 
 impl Default for LightConfig {
@@ -67,6 +103,7 @@ impl Default for LightConfig {
             height: 26,
             decay: 0.95,
             gui_delay: 0,
+            bass_band: "bass".to_string(),
         }
     }
 }
@@ -1,13 +1,221 @@
+use std::{collections::VecDeque, f32::consts::PI, sync::Arc};
+
 use ebur128::EbuR128;
 use serde::{Deserialize, Serialize};
 
-use crate::util::profile_function;
+use crate::{cfg::AnalysisConfig, util::profile_function};
 
-#[derive(Default, Clone)]
+#[derive(Clone)]
 pub struct LoudnessData {
     pub st: f64,
     pub m: f64,
+    /// Gated loudness of everything so far
+    pub integrated: f64,
+    /// Loudness range (LRA) of everything so far in LU
+    pub range: f64,
+    /// Gain `LoudnessConfig::normalize` applies, frozen while `silent`
     pub gain: f64,
+    /// Gain the limiter applied on top of `gain` at the end of the last hop
+    pub limiter_gain: f32,
+    /// Whether the momentary loudness is below `SilenceConfig::threshold`
+    /// (and hasn't risen above it by `SilenceConfig::hysteresis` yet)
+    pub silent: bool,
+    /// Whether it has been silent for at least `SilenceConfig::hold`
+    pub idle: bool,
+    /// Seconds since going idle, 0 when not idle
+    pub idle_for: f32,
+    silent_for: f32,
+    limiter: Limiter,
+}
+
+impl LoudnessData {
+    pub fn blank(cfg: &AnalysisConfig, channels: usize) -> Self {
+        Self {
+            st: f64::NEG_INFINITY,
+            m: f64::NEG_INFINITY,
+            integrated: f64::NEG_INFINITY,
+            range: 0.0,
+            gain: 1.0,
+            limiter_gain: 1.0,
+            silent: false,
+            idle: false,
+            idle_for: 0.0,
+            silent_for: 0.0,
+            limiter: Limiter::new(cfg, channels),
+        }
+    }
+
+    /// `samples` are the interleaved samples of the new hop, before
+    /// normalization
+    pub fn advance(mut self, cfg: &AnalysisConfig, samples: &[f32], ebur: &mut EbuR128) -> Self {
+        profile_function!();
+        let c = &cfg.loudness;
+        ebur.add_frames_f32(samples).unwrap();
+        self.st = ebur.loudness_shortterm().unwrap();
+        self.m = ebur.loudness_momentary().unwrap();
+        self.integrated = ebur.loudness_global().unwrap();
+        self.range = ebur.loudness_range().unwrap();
+
+        let s = &c.silence;
+        let threshold = if self.silent {
+            s.threshold + s.hysteresis
+        } else {
+            s.threshold
+        };
+        self.silent = s.enabled && self.m < threshold;
+        self.silent_for = if self.silent {
+            self.silent_for + cfg.hop_duration()
+        } else {
+            0.0
+        };
+        self.idle = self.silent && self.silent_for >= s.hold;
+        self.idle_for = if self.idle {
+            self.silent_for - s.hold
+        } else {
+            0.0
+        };
+        // keep the gain from before instead of boosting the noise floor
+        if !self.silent {
+            self.gain = match c.reference {
+                // the integrated loudness is -inf until enough has been heard
+                Reference::Integrated if self.integrated.is_finite() => c.gain(self.integrated),
+                _ => c.gain(self.st),
+            };
+        }
+        self
+    }
+
+    /// https://github.com/sdroege/ebur128/blob/main/examples/normalize.rs
+    ///
+    /// The gained samples go through the limiter, which delays them by
+    /// `LimiterConfig::lookahead`, and are clamped to `-1.0..=1.0`.
+    pub fn normalize(&mut self, cfg: &AnalysisConfig, mut samples: Vec<f32>) -> Vec<f32> {
+        profile_function!();
+        let c = &cfg.loudness;
+
+        if !c.normalize {
+            return samples;
+        }
+
+        let gain = self.gain as f32;
+        samples.iter_mut().for_each(|s| *s *= gain);
+        if c.limiter.enabled {
+            self.limiter.process(cfg, &mut samples);
+            self.limiter_gain = self.limiter.gain;
+        } else {
+            samples.iter_mut().for_each(|s| *s = s.clamp(-1.0, 1.0));
+        }
+        samples
+    }
+}
+
+/// Taps of the interpolation filter the true peaks are estimated with
+const TAPS: usize = 8;
+/// Oversampling factor of the true peak estimate
+const OVERSAMPLING: usize = 4;
+
+/// Look-ahead limiter on the true (inter-sample) peaks of all channels.
+///
+/// The gain every frame needs is held at its minimum for one frame more than
+/// the look-ahead and then smoothed with a moving average of the look-ahead,
+/// so every frame of the average has seen the peak by the time it leaves the
+/// delay line.
+#[derive(Clone)]
+struct Limiter {
+    channels: usize,
+    /// Samples of the frames that haven't been output yet
+    delay: VecDeque<f32>,
+    /// Last `TAPS` samples of every channel
+    history: Vec<VecDeque<f32>>,
+    /// Interpolation filters for the points between two samples
+    phases: Arc<[[f32; TAPS]]>,
+    /// Candidates for the minimum required gain as (frame, gain)
+    minimum: VecDeque<(usize, f32)>,
+    /// Held minimums in the moving average and their sum
+    held: VecDeque<f32>,
+    sum: f64,
+    frame: usize,
+    gain: f32,
+}
+
+impl Limiter {
+    /// The look-ahead is read once here, since it sets the latency
+    fn new(cfg: &AnalysisConfig, channels: usize) -> Self {
+        let len = (cfg.loudness.limiter.lookahead * cfg.fft.sample_rate as f32).max(1.0) as usize;
+        // windowed sinc between `history[TAPS / 2 - 1]` and `history[TAPS / 2]`
+        let phases = (1..OVERSAMPLING)
+            .map(|k| {
+                std::array::from_fn(|j| {
+                    let x = j as f32 - (TAPS / 2 - 1) as f32 - k as f32 / OVERSAMPLING as f32;
+                    let sinc = if x == 0.0 {
+                        1.0
+                    } else {
+                        f32::sin(PI * x) / (PI * x)
+                    };
+                    sinc * 0.5 * (1.0 + f32::cos(PI * x / (TAPS / 2) as f32))
+                })
+            })
+            .collect();
+        Self {
+            channels,
+            // the true peak is found `TAPS / 2 - 1` frames late
+            delay: VecDeque::from(vec![0.0; (len + TAPS / 2 - 1) * channels]),
+            history: vec![VecDeque::from(vec![0.0; TAPS]); channels],
+            phases,
+            minimum: VecDeque::new(),
+            held: VecDeque::from(vec![1.0; len]),
+            sum: len as f64,
+            frame: 0,
+            gain: 1.0,
+        }
+    }
+
+    fn process(&mut self, cfg: &AnalysisConfig, samples: &mut [f32]) {
+        let l = &cfg.loudness.limiter;
+        let ceiling = 10f32.powf(l.ceiling / 20.0);
+        let release = f32::exp(-1.0 / (l.release * cfg.fft.sample_rate as f32));
+        let len = self.held.len();
+        for frame in samples.chunks_exact_mut(self.channels) {
+            let mut peak = 0f32;
+            for (history, &s) in self.history.iter_mut().zip(frame.iter()) {
+                history.pop_front();
+                history.push_back(s);
+                peak = peak.max(history[TAPS / 2].abs());
+                for phase in self.phases.iter() {
+                    let x = history.iter().zip(phase).map(|(h, c)| h * c).sum::<f32>();
+                    peak = peak.max(x.abs());
+                }
+            }
+            let required = f32::min(1.0, ceiling / peak);
+
+            while self.minimum.back().is_some_and(|&(_, g)| g >= required) {
+                self.minimum.pop_back();
+            }
+            self.minimum.push_back((self.frame, required));
+            while self
+                .minimum
+                .front()
+                .is_some_and(|&(i, _)| i + len < self.frame)
+            {
+                self.minimum.pop_front();
+            }
+            let held = self.minimum.front().unwrap().1;
+            self.held.push_back(held);
+            self.sum += held as f64 - self.held.pop_front().unwrap() as f64;
+            let target = (self.sum / len as f64) as f32;
+            self.gain = if target < self.gain {
+                target
+            } else {
+                target + (self.gain - target) * release
+            };
+            self.frame += 1;
+
+            for s in frame {
+                self.delay.push_back(*s);
+                *s = (self.delay.pop_front().unwrap() * self.gain).clamp(-1.0, 1.0);
+            }
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -16,51 +224,176 @@ pub struct LoudnessConfig {
     pub target_lufs: f64,
     pub factor: f64,
     pub normalize: bool,
+    pub reference: Reference,
+    pub limiter: LimiterConfig,
+    pub silence: SilenceConfig,
+}
+
+/// Loudness the normalization gain is based on
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Reference {
+    /// Follows the song, so quiet parts get as loud as the rest
+    ShortTerm,
+    /// Keeps the dynamics of the song and evens out how loud it was mastered
+    Integrated,
 }
 
 impl LoudnessConfig {
     pub fn gain(&self, loudness: f64) -> f64 {
         10f64.powf((self.target_lufs - loudness) / 20.0 * self.factor)
     }
+}
 
-    /// https://github.com/sdroege/ebur128/blob/main/examples/normalize.rs
-    pub fn normalize(
-        &self,
-        samples: impl ExactSizeIterator<Item = i16>,
-        ebur: &mut EbuR128,
-    ) -> Vec<i16> {
-        profile_function!();
-
-        if !self.normalize {
-            return samples.collect();
+impl Default for LoudnessConfig {
+    fn default() -> Self {
+        Self {
+            target_lufs: -20.0,
+            factor: 0.4,
+            normalize: true,
+            reference: Reference::ShortTerm,
+            limiter: Default::default(),
+            silence: Default::default(),
         }
-
-        let samples = samples.collect::<Vec<_>>();
-        ebur.add_frames_i16(&samples).unwrap();
-        let loudness = ebur.loudness_shortterm().unwrap();
-
-        samples
-            .into_iter()
-            .map(move |s| (s as f64 * self.gain(loudness)).clamp(0.0, i16::MAX as f64) as i16)
-            .collect()
     }
+}
 
-    pub fn data(&self, ebur: &EbuR128) -> LoudnessData {
-        let st = ebur.loudness_shortterm().unwrap();
-        LoudnessData {
-            st,
-            m: ebur.loudness_momentary().unwrap(),
-            gain: self.gain(st)
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct LimiterConfig {
+    pub enabled: bool,
+    /// Highest true peak in dBTP
+    pub ceiling: f32,
+    /// Seconds the limiter looks ahead, only read when the analysis starts
+    pub lookahead: f32,
+    /// Seconds for the gain to recover most of the way after a peak
+    pub release: f32,
+}
+
+impl Default for LimiterConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ceiling: -1.0,
+            lookahead: 0.005,
+            release: 0.05,
         }
     }
 }
 
-impl Default for LoudnessConfig {
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct SilenceConfig {
+    pub enabled: bool,
+    /// Momentary loudness in LUFS below which the input counts as silent
+    pub threshold: f64,
+    /// How far above `threshold` the loudness has to get to stop being silent
+    pub hysteresis: f64,
+    /// Seconds of silence before going idle
+    pub hold: f32,
+}
+
+impl Default for SilenceConfig {
     fn default() -> Self {
         Self {
-            target_lufs: -20.0,
-            factor: 0.4,
-            normalize: true,
+            enabled: true,
+            threshold: -60.0,
+            hysteresis: 6.0,
+            hold: 2.0,
         }
     }
 }
+
+#[test]
+fn test_silence() {
+    use crate::Analyzer;
+    use std::f32::consts::TAU;
+
+    let cfg = AnalysisConfig::default();
+    let sample_rate = cfg.fft.sample_rate;
+    let tone = |seconds: usize| {
+        (0..sample_rate * seconds)
+            .map(|i| f32::sin(i as f32 / sample_rate as f32 * 440.0 * TAU) * 0.25)
+            .collect::<Vec<_>>()
+    };
+
+    let mut analyzer = Analyzer::new(cfg.clone(), 1, sample_rate as u32);
+    analyzer.push(&tone(2));
+    while analyzer.next_frame().is_some() {}
+    let loudness = &analyzer.state().loudness;
+    assert!(!loudness.silent && !loudness.idle);
+
+    let mut gain = loudness.gain;
+    analyzer.push(&vec![0.0; sample_rate * 4]);
+    let mut went_idle = None;
+    while let Some(state) = analyzer.next_frame() {
+        let loudness = &state.loudness;
+        if loudness.silent {
+            assert_eq!(loudness.gain, gain);
+        }
+        gain = loudness.gain;
+        if loudness.idle && went_idle.is_none() {
+            went_idle = Some(state.hop);
+        }
+    }
+    let loudness = &analyzer.state().loudness;
+    assert!(loudness.idle && loudness.idle_for > 0.0);
+    let went_idle = went_idle.unwrap() as f32 * cfg.hop_duration() - 2.0;
+    // the momentary loudness takes a bit to fall below the threshold
+    assert!(
+        went_idle >= cfg.loudness.silence.hold && went_idle < 3.0,
+        "{went_idle}"
+    );
+    let idle = cfg.paint.idle.color(loudness.idle_for);
+    assert!(analyzer.state().paint.colors.iter().all(|c| *c == idle));
+
+    analyzer.push(&tone(1));
+    while analyzer.next_frame().is_some() {}
+    assert!(!analyzer.state().loudness.silent && !analyzer.state().loudness.idle);
+}
+
+#[test]
+fn test_limiter() {
+    use crate::Analyzer;
+    use std::f32::consts::TAU;
+
+    let mut cfg = AnalysisConfig::default();
+    // push everything way above full scale
+    cfg.loudness.target_lufs = 0.0;
+    cfg.loudness.factor = 1.0;
+    let sample_rate = cfg.fft.sample_rate;
+    // a quarter of the sample rate shifted by 45 degrees never samples its
+    // peaks, so they're all inter-sample peaks
+    let samples = (0..sample_rate * 3)
+        .map(|i| f32::sin(i as f32 * TAU / 4.0 + TAU / 8.0) * 0.1)
+        .collect::<Vec<_>>();
+
+    let mut analyzer = Analyzer::new(cfg.clone(), 1, sample_rate as u32);
+    analyzer.push(&samples);
+    while analyzer.next_frame().is_some() {}
+    let state = analyzer.state();
+    let loudness = &state.loudness;
+    assert!(loudness.integrated.is_finite() && loudness.range >= 0.0);
+    assert!(loudness.gain > 1.0 && loudness.limiter_gain < 1.0);
+
+    let ceiling = 10f32.powf(cfg.loudness.limiter.ceiling / 20.0);
+    let max = state.buffer.iter().cloned().fold(f32::MIN, f32::max);
+    let min = state.buffer.iter().cloned().fold(f32::MAX, f32::min);
+    // the true peak is sqrt(2) times the sample peak
+    let true_peak = max * 2f32.sqrt();
+    assert!(true_peak <= ceiling * 1.01, "{true_peak}");
+    assert!(true_peak >= ceiling * 0.9, "{true_peak}");
+    assert!((max + min).abs() < 0.01, "{max} {min}");
+
+    // a single sample peak only sets the required gain for one frame
+    let mut limiter = Limiter::new(&cfg, 1);
+    let mut samples = vec![0.0; 1024];
+    samples[100] = 4.0;
+    limiter.process(&cfg, &mut samples);
+    // the other samples are silent, so the true peak is the sample peak
+    let true_peak = samples.iter().fold(0f32, |m, s| m.max(s.abs()));
+    assert!(
+        true_peak <= ceiling && true_peak >= ceiling * 0.99,
+        "{true_peak}"
+    );
+}
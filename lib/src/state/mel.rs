@@ -0,0 +1,145 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cfg::AnalysisConfig,
+    unit::{Db, Power},
+    util::profile_function,
+};
+
+use super::fft::FftData;
+
+/// Weight of every raw bin that falls into a band
+type Filter = Vec<(usize, f32)>;
+
+/// Band energies of a mel filterbank, low bands first
+#[derive(Clone)]
+pub struct MelData {
+    /// Weighted average of the bin powers in each band, so bands of different
+    /// widths are comparable with each other and with `FftData::power`
+    pub power: Vec<Power>,
+    pub db: Vec<Db>,
+    filters: Arc<[Filter]>,
+}
+
+impl MelData {
+    pub fn blank(cfg: &AnalysisConfig) -> Self {
+        let filters = filters(cfg);
+        Self {
+            power: vec![Power(0.0); filters.len()],
+            db: vec![Db(0.0); filters.len()],
+            filters,
+        }
+    }
+
+    pub fn advance(mut self, fft: &FftData) -> Self {
+        profile_function!();
+        for ((power, db), filter) in self
+            .power
+            .iter_mut()
+            .zip(&mut self.db)
+            .zip(self.filters.iter())
+        {
+            *power = Power(filter.iter().map(|&(j, w)| fft.raw[j].norm_sqr() * w).sum());
+            *db = (*power).into();
+        }
+        self
+    }
+}
+
+pub fn hz_to_mel(hz: f32) -> f32 {
+    2595.0 * f32::log10(1.0 + hz / 700.0)
+}
+
+pub fn mel_to_hz(mel: f32) -> f32 {
+    700.0 * (10f32.powf(mel / 2595.0) - 1.0)
+}
+
+/// Triangular filters evenly spaced on the mel scale, normalized to a sum of 1
+fn filters(cfg: &AnalysisConfig) -> Arc<[Filter]> {
+    let c = &cfg.mel;
+    let (lo, hi) = (hz_to_mel(c.min_frequency), hz_to_mel(c.max_frequency));
+    let edges = (0..c.bands + 2)
+        .map(|i| mel_to_hz(lo + (hi - lo) * i as f32 / (c.bands + 1) as f32))
+        .collect::<Vec<_>>();
+
+    edges
+        .windows(3)
+        .map(|e| {
+            let (start, center, end) = (e[0], e[1], e[2]);
+            let mut filter = (cfg.hz_to_idx(start)..=cfg.hz_to_idx(end) + 1)
+                .filter(|&j| j < cfg.raw_len())
+                .map(|j| {
+                    let f = cfg.idx_to_hz(j);
+                    let w = if f < center {
+                        (f - start) / (center - start)
+                    } else {
+                        (end - f) / (end - center)
+                    };
+                    (j, w)
+                })
+                .filter(|&(_, w)| w > 0.0)
+                .collect::<Vec<_>>();
+            // bands narrower than a bin still get the closest one
+            if filter.is_empty() {
+                let j = (center * cfg.frame_duration()).round() as usize;
+                filter.push((j.min(cfg.raw_len() - 1), 1.0));
+            }
+
+            let sum = filter.iter().map(|(_, w)| w).sum::<f32>();
+            filter.iter_mut().for_each(|(_, w)| *w /= sum);
+            filter
+        })
+        .collect()
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct MelConfig {
+    pub bands: usize,
+    pub min_frequency: f32,
+    pub max_frequency: f32,
+}
+
+impl Default for MelConfig {
+    fn default() -> Self {
+        Self {
+            bands: 40,
+            min_frequency: 20.0,
+            max_frequency: 10000.0,
+        }
+    }
+}
+
+#[test]
+fn test_band_peak() {
+    use crate::Analyzer;
+    use std::f32::consts::TAU;
+
+    let mut cfg = AnalysisConfig::default();
+    cfg.loudness.normalize = false;
+    let c = &cfg.mel;
+    let (lo, hi) = (hz_to_mel(c.min_frequency), hz_to_mel(c.max_frequency));
+    let center = |i: usize| mel_to_hz(lo + (hi - lo) * (i + 1) as f32 / (c.bands + 1) as f32);
+
+    for band in [5, 20, 35] {
+        let f = center(band);
+        let samples = (0..cfg.fft.frame_len * 2)
+            .map(|i| f32::sin(i as f32 / cfg.fft.sample_rate as f32 * f * TAU) * 0.25)
+            .collect::<Vec<_>>();
+        let mut analyzer = Analyzer::new(cfg.clone(), 1, cfg.fft.sample_rate as u32);
+        analyzer.push(&samples);
+        while analyzer.next_frame().is_some() {}
+
+        let (max, _) = analyzer
+            .state()
+            .mel
+            .power
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.0.total_cmp(&b.1.0))
+            .unwrap();
+        assert_eq!(max, band);
+    }
+}
@@ -0,0 +1,200 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{cfg::AnalysisConfig, util::profile_function};
+
+use super::hps::HpsData;
+
+/// Predominant pitch tracker on the harmonic spectrum.
+///
+/// Every candidate pitch gets a salience from the weighted sum of the
+/// spectrum at its harmonics. The candidates are then tracked with the
+/// forward pass of a Viterbi decoder, so the pitch prefers small steps over
+/// jumping between octaves and neighbouring notes every hop.
+#[derive(Clone)]
+pub struct MelodyData {
+    /// Fundamental frequency of the melody in Hz
+    pub f0: f32,
+    /// `f0` as a (fractional) MIDI note number
+    pub midi: f32,
+    /// How much the strongest pitch stands out from the rest, from 0 to 1
+    pub confidence: f32,
+    /// Whether `confidence` is above `MelodyConfig::voicing_threshold`
+    pub voiced: bool,
+    /// Accumulated log probability of every candidate
+    score: Vec<f32>,
+    salience: Vec<f32>,
+    candidates: Arc<[f32]>,
+}
+
+impl MelodyData {
+    pub fn blank(cfg: &AnalysisConfig) -> Self {
+        let candidates = candidates(cfg);
+        Self {
+            f0: 0.0,
+            midi: 0.0,
+            confidence: 0.0,
+            voiced: false,
+            score: vec![0.0; candidates.len()],
+            salience: vec![0.0; candidates.len()],
+            candidates,
+        }
+    }
+
+    pub fn advance(mut self, cfg: &AnalysisConfig, hps: &HpsData) -> Self {
+        profile_function!();
+        let c = &cfg.melody;
+        if self.candidates.is_empty() {
+            return self;
+        }
+
+        // linearly interpolated magnitude at a frequency
+        let magnitude = |hz: f32| {
+            let x = hz * cfg.frame_duration() - cfg.min_idx() as f32;
+            let i = x.floor() as usize;
+            if x < 0.0 || i + 1 >= hps.harmonic.len() {
+                return 0.0;
+            }
+            let t = x - i as f32;
+            hps.harmonic[i].norm() * (1.0 - t) + hps.harmonic[i + 1].norm() * t
+        };
+        for (salience, &f) in self.salience.iter_mut().zip(self.candidates.iter()) {
+            *salience = (1..=c.harmonics)
+                .map(|h| c.harmonic_decay.powi(h as i32 - 1) * magnitude(f * h as f32))
+                .sum();
+        }
+
+        let max = self.salience.iter().cloned().fold(0.0, f32::max);
+        if max <= 0.0 {
+            self.confidence = 0.0;
+            self.voiced = false;
+            return self;
+        }
+        let mean = self.salience.iter().sum::<f32>() / self.salience.len() as f32;
+        self.confidence = 1.0 - mean / max;
+        self.voiced = self.confidence >= c.voicing_threshold;
+
+        // forward pass with a gaussian transition in semitones
+        let steps = c.steps_per_semitone as f32;
+        let reach = (3.0 * c.transition_width * steps).ceil() as usize;
+        let prev = self.score.clone();
+        for (j, score) in self.score.iter_mut().enumerate() {
+            let best = (j.saturating_sub(reach)..(j + reach + 1).min(prev.len()))
+                .map(|k| {
+                    let jump = (j as f32 - k as f32) / steps / c.transition_width;
+                    prev[k] - 0.5 * jump * jump
+                })
+                .fold(f32::NEG_INFINITY, f32::max);
+            *score = best + f32::ln(self.salience[j] / max + 1e-3);
+        }
+        let (j, &top) = self
+            .score
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .unwrap();
+        self.score.iter_mut().for_each(|s| *s -= top);
+
+        // parabolic interpolation of the salience around the chosen pitch
+        let offset = match (j.checked_sub(1), self.salience.get(j + 1)) {
+            (Some(l), Some(&r)) => {
+                let (l, m) = (self.salience[l], self.salience[j]);
+                let curvature = l - 2.0 * m + r;
+                if curvature < 0.0 {
+                    0.5 * (l - r) / curvature
+                } else {
+                    0.0
+                }
+            }
+            _ => 0.0,
+        };
+        self.f0 = self.candidates[j] * 2f32.powf(offset / (12.0 * steps));
+        self.midi = hz_to_midi(self.f0);
+        self
+    }
+}
+
+pub fn hz_to_midi(hz: f32) -> f32 {
+    69.0 + 12.0 * f32::log2(hz / 440.0)
+}
+
+/// Candidate pitches spaced evenly in cents
+fn candidates(cfg: &AnalysisConfig) -> Arc<[f32]> {
+    let c = &cfg.melody;
+    let steps = 12.0 * c.steps_per_semitone as f32;
+    let count = (f32::log2(c.max_frequency / c.min_frequency) * steps).floor() as usize + 1;
+    (0..count)
+        .map(|i| c.min_frequency * 2f32.powf(i as f32 / steps))
+        .collect()
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct MelodyConfig {
+    pub min_frequency: f32,
+    pub max_frequency: f32,
+    /// Resolution of the candidate pitches
+    pub steps_per_semitone: usize,
+    /// Number of harmonics summed into the salience
+    pub harmonics: usize,
+    /// Weight of every harmonic relative to the one below it
+    pub harmonic_decay: f32,
+    /// Standard deviation of the pitch change between hops in semitones
+    pub transition_width: f32,
+    /// Confidence above which the melody counts as voiced
+    pub voicing_threshold: f32,
+}
+
+impl Default for MelodyConfig {
+    fn default() -> Self {
+        Self {
+            min_frequency: 80.0,
+            max_frequency: 1000.0,
+            steps_per_semitone: 5,
+            harmonics: 8,
+            harmonic_decay: 0.8,
+            transition_width: 1.0,
+            voicing_threshold: 0.5,
+        }
+    }
+}
+
+#[test]
+fn test_melody() {
+    use crate::Analyzer;
+    use std::f32::consts::TAU;
+
+    let mut cfg = AnalysisConfig::default();
+    cfg.loudness.normalize = false;
+    let sample_rate = cfg.fft.sample_rate;
+    let note = |midi: f32| {
+        let f0 = 440.0 * 2f32.powf((midi - 69.0) / 12.0);
+        (0..sample_rate / 2).map(move |i| {
+            let t = i as f32 / sample_rate as f32;
+            (1..=4)
+                .map(|h| f32::sin(t * f0 * h as f32 * TAU) / h as f32)
+                .sum::<f32>()
+                * 0.2
+        })
+    };
+
+    let mut analyzer = Analyzer::new(cfg.clone(), 1, sample_rate as u32);
+    // A3 C4 E4 D4
+    for midi in [57.0, 60.0, 64.0, 62.0] {
+        analyzer.push(&note(midi).collect::<Vec<_>>());
+        while analyzer.next_frame().is_some() {}
+
+        let melody = &analyzer.state().melody;
+        assert!(
+            (melody.midi - midi).abs() < 0.3,
+            "{} != {midi}",
+            melody.midi
+        );
+        assert!(melody.voiced, "{}", melody.confidence);
+    }
+
+    analyzer.push(&vec![0.0; sample_rate]);
+    while analyzer.next_frame().is_some() {}
+    assert!(!analyzer.state().melody.voiced);
+}
@@ -0,0 +1,176 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{cfg::AnalysisConfig, util::profile_function};
+
+use super::{AudibleSpec, hps::HpsData};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Onset {
+    /// Seconds since the start of the analysis
+    pub time: f32,
+    /// Height of the detection function at the onset
+    pub strength: f32,
+}
+
+/// Onset detection from the spectral flux of the percussive spectrum
+#[derive(Clone)]
+pub struct OnsetData {
+    /// Spectral flux of this hop
+    pub flux: f32,
+    /// What a peak in `flux` has to exceed to count as an onset
+    pub threshold: f32,
+    /// Onset found in this hop. Peaks are only known once the flux goes down
+    /// again, so this lags one hop behind.
+    pub onset: Option<Onset>,
+    picker: PeakPicker,
+    prev: AudibleSpec<f32>,
+}
+
+impl OnsetData {
+    pub fn blank(cfg: &AnalysisConfig) -> Self {
+        Self {
+            flux: 0.0,
+            threshold: 0.0,
+            onset: None,
+            picker: PeakPicker::new(&cfg.onset.peak),
+            prev: AudibleSpec::blank_default(cfg),
+        }
+    }
+
+    /// `hop` is the index of the hop `hps` was computed from
+    pub fn advance(mut self, cfg: &AnalysisConfig, hps: &HpsData, hop: usize) -> Self {
+        profile_function!();
+        let compression = cfg.onset.compression;
+        let mut flux = 0.0;
+        self.prev.mutate(|i, prev| {
+            let mag = f32::ln_1p(compression * hps.percussive[i].norm());
+            flux += f32::max(0.0, mag - *prev);
+            *prev = mag;
+        });
+        self.flux = flux / self.prev.len() as f32;
+
+        let peak = self
+            .picker
+            .consume(&cfg.onset.peak, cfg.hop_duration(), self.flux);
+        self.threshold = self.picker.threshold;
+        self.onset = peak.map(|strength| Onset {
+            time: hop.saturating_sub(1) as f32 * cfg.hop_duration(),
+            strength,
+        });
+        self
+    }
+}
+
+/// Finds peaks in a detection function that is fed one value per hop.
+///
+/// A peak is a local maximum above an adaptive threshold of `multiplier`
+/// times the moving median plus `delta`. It is reported once the next value
+/// is known, so one hop late.
+#[derive(Clone)]
+pub struct PeakPicker {
+    median: median::Filter<f32>,
+    /// The last two values, oldest first
+    last: [f32; 2],
+    /// Seconds since the last peak
+    since_peak: f32,
+    pub threshold: f32,
+}
+
+impl PeakPicker {
+    pub fn new(cfg: &PeakConfig) -> Self {
+        Self {
+            median: median::Filter::new(cfg.window),
+            last: [0.0; 2],
+            since_peak: f32::INFINITY,
+            threshold: 0.0,
+        }
+    }
+
+    /// Returns the height of the previous value if it was a peak
+    pub fn consume(&mut self, cfg: &PeakConfig, hop_duration: f32, x: f32) -> Option<f32> {
+        let [before, peak] = self.last;
+        self.last = [peak, x];
+        self.threshold = self.median.consume(x) * cfg.multiplier + cfg.delta;
+        self.since_peak += hop_duration;
+
+        let is_peak = peak > before && peak >= x && peak > self.threshold;
+        if is_peak && self.since_peak >= cfg.min_interval {
+            self.since_peak = 0.0;
+            Some(peak)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct PeakConfig {
+    /// Length of the moving median in hops
+    pub window: usize,
+    pub multiplier: f32,
+    pub delta: f32,
+    /// Minimum time between two peaks in seconds
+    pub min_interval: f32,
+}
+
+impl Default for PeakConfig {
+    fn default() -> Self {
+        Self {
+            window: 12,
+            multiplier: 1.5,
+            delta: 0.01,
+            min_interval: 0.05,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct OnsetConfig {
+    /// Magnitudes are compressed with `ln(1 + compression * x)` before taking
+    /// the flux
+    pub compression: f32,
+    pub peak: PeakConfig,
+}
+
+impl Default for OnsetConfig {
+    fn default() -> Self {
+        Self {
+            compression: 1.0,
+            peak: Default::default(),
+        }
+    }
+}
+
+#[test]
+fn test_click_train() {
+    use crate::Analyzer;
+
+    let mut cfg = AnalysisConfig::default();
+    cfg.loudness.normalize = false;
+    let sample_rate = cfg.fft.sample_rate;
+    let clicks = [0.5, 1.0, 1.25, 2.0];
+
+    let mut samples = vec![0.0; sample_rate * 3];
+    for t in clicks {
+        let start = (t * sample_rate as f32) as usize;
+        for (i, s) in samples[start..start + 64].iter_mut().enumerate() {
+            *s = if i % 2 == 0 { 0.8 } else { -0.8 };
+        }
+    }
+
+    let mut analyzer = Analyzer::new(cfg.clone(), 1, sample_rate as u32);
+    analyzer.push(&samples);
+    let mut onsets = Vec::new();
+    while let Some(state) = analyzer.next_frame() {
+        onsets.extend(state.onset.onset);
+    }
+
+    assert_eq!(onsets.len(), clicks.len(), "{onsets:?}");
+    for (onset, t) in onsets.iter().zip(clicks) {
+        // a click shows up in every frame that covers it
+        let frame = cfg.fft.frame_len as f32 / sample_rate as f32;
+        assert!(onset.time >= t && onset.time <= t + frame, "{onset:?} {t}");
+    }
+}
@@ -1,4 +1,4 @@
-use std::iter;
+use std::{f32::consts::TAU, iter};
 
 use ecolor::Color32;
 use serde::{Deserialize, Serialize};
@@ -11,7 +11,7 @@ use crate::{
     util::profile_function,
 };
 
-use super::{light::LightData, power::PowerData};
+use super::{light::LightData, loudness::LoudnessData, power::PowerData};
 
 #[derive(Clone)]
 pub struct PaintData {
@@ -89,8 +89,14 @@ impl PaintData {
         easing: &mut EasingFunctions,
         light: &LightData,
         power: &PowerData,
+        loudness: &LoudnessData,
     ) -> Self {
         profile_function!();
+        if loudness.idle {
+            let color = cfg.idle.color(loudness.idle_for);
+            self.colors.fill(color);
+            return self;
+        }
         let mut ctx = self.ctx(cfg, easing, light, power);
 
         self.pix.fill(Color32::BLACK.into_color());
@@ -330,11 +336,40 @@ impl Canvas<Oklch> {
     }
 }
 
+/// What to show while `LoudnessData::idle`
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum IdleBehavior {
+    Black,
+    /// Slowly pulse all lights in gray, starting from black
+    Breathe {
+        /// Seconds per breath
+        period: f32,
+        /// Peak brightness from 0 to 1
+        brightness: f32,
+    },
+}
+
+impl IdleBehavior {
+    /// Color of every light after `idle_for` seconds of being idle
+    pub fn color(&self, idle_for: f32) -> Color32 {
+        match *self {
+            IdleBehavior::Black => Color32::BLACK,
+            IdleBehavior::Breathe { period, brightness } => {
+                let x = 0.5 - 0.5 * f32::cos(idle_for / period * TAU);
+                Color32::from_gray((x * brightness * 255.0) as u8)
+            }
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone)]
 #[serde(default)]
 pub struct PaintConfig {
     pub roll_len: Vec<u32>,
     pub roll_opacity: Vec<f32>,
+    pub idle: IdleBehavior,
 }
 
 impl Default for PaintConfig {
@@ -342,6 +377,10 @@ impl Default for PaintConfig {
         Self {
             roll_len: vec![12, 5, 3, 2, 2, 1, 1],
             roll_opacity: vec![1.0, 0.9, 0.8, 0.7, 0.6, 0.5, 0.4],
+            idle: IdleBehavior::Breathe {
+                period: 6.0,
+                brightness: 0.1,
+            },
         }
     }
 }
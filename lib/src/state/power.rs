@@ -1,12 +1,14 @@
-use std::ops::Sub;
+use std::{f32::consts::TAU, ops::Sub};
+
+use derive_more::derive::Deref;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     cfg::AnalysisConfig,
-    unit::Power,
     util::{RollingAverage, profile_function},
 };
 
-use super::{AudibleSpec, hps::HpsData};
+use super::{AudibleSpec, channel::ChannelData, cqt::CqtData, fft::FftData, hps::HpsData};
 
 #[derive(Clone)]
 pub struct PowerData {
@@ -17,13 +19,32 @@ pub struct PowerData {
     pub p_power_raw: DData<f32>,
     // pub dp: f32,
     // pub p_filtered: AudibleSpec<Power>,
+    /// Power of `p_filtered` without the band driving the bass light (see
+    /// `LightConfig::bass_band`)
     pub p_filtered_power: DData<f32>,
-    pub p_bass_power: DData<f32>,
+    /// Power of every band in `AnalysisConfig::bands`, in the same order
+    pub bands: Vec<(String, DData<f32>)>,
     // pub dp_filtered: f32,
     pub ratio_h_p: RollingAverage,
 
+    /// Power of every pitch class summed over all octaves, starting at A
     pub octave_power: [f32; 12],
+    /// Where in the register each pitch class sits, from 0 to 1 (see
+    /// `NotesConfig::octave_weights`)
     pub average_octave: [f32; 12],
+    /// Power of every pitch class in every octave, lowest octave first.
+    /// Pitch classes start at A like `octave_power`.
+    pub note_power: Vec<[f32; 12]>,
+
+    /// Estimated deviation of the recording from A4 = 440Hz in cents, which
+    /// the note boundaries are shifted by
+    pub tuning: f32,
+    tuning_cos: RollingAverage,
+    tuning_sin: RollingAverage,
+
+    /// Power of each input channel, empty unless `FftConfig::per_channel` is
+    /// set
+    pub channel_power: Vec<f32>,
 }
 
 impl PowerData {
@@ -35,14 +56,26 @@ impl PowerData {
             p_power_raw: Default::default(),
             // p_filtered: AudibleSpec::blank_default(cfg),
             p_filtered_power: Default::default(),
-            p_bass_power: Default::default(),
+            bands: Vec::new(),
             ratio_h_p: RollingAverage::new(5),
             octave_power: Default::default(),
             average_octave: Default::default(),
+            note_power: vec![Default::default(); cfg.notes.octaves],
+            tuning: 0.0,
+            tuning_cos: RollingAverage::new(cfg.tuning.window.max(1)),
+            tuning_sin: RollingAverage::new(cfg.tuning.window.max(1)),
+            channel_power: Vec::new(),
         }
     }
 
-    pub fn new(cfg: &AnalysisConfig, data: &HpsData, prev: PowerData) -> Self {
+    pub fn new(
+        cfg: &AnalysisConfig,
+        fft: &FftData,
+        data: &HpsData,
+        cqt: &CqtData,
+        channels: &ChannelData,
+        prev: PowerData,
+    ) -> Self {
         profile_function!();
         let h_power_raw = data.harmonic.power(cfg);
         let r_power_raw = data.residual.power(cfg);
@@ -50,49 +83,69 @@ impl PowerData {
 
         let p_power_raw = data.percussive.power(cfg);
 
-        let p_bass_power = AudibleSpec(
-            data.p_filtered.0[0..8]
-                .iter()
-                .enumerate()
-                .map(|(i, &x)| Power(*x * f32::min((8 - i) as f32 / 4.0, 1.0)))
-                .collect(),
-        )
-        .power(cfg);
-        let p_filtered_power = data.p_filtered.power(cfg) - p_bass_power;
+        let bands = cfg
+            .bands
+            .iter()
+            .map(|band| {
+                let prev = prev.band(&band.name).cloned().unwrap_or_default();
+                (band.name.clone(), prev.advance(band.power(cfg, fft, data)))
+            })
+            .collect::<Vec<_>>();
+        let bass_power = bands
+            .iter()
+            .find(|(name, _)| *name == cfg.light.bass_band)
+            .map_or(0.0, |(_, d)| d.val);
+        let p_filtered_power = data.p_filtered.power(cfg) - bass_power;
 
         let mut ratio_h_p = prev.ratio_h_p;
         ratio_h_p.consume(ratio(h_power_raw, p_filtered_power));
 
-        // A4 = 440Hz
-        // we start at A2 and keep going for 4 octaves
-        const A2: f64 = 110.0;
+        let (mut tuning_cos, mut tuning_sin) = (prev.tuning_cos, prev.tuning_sin);
+        let tuning = if cfg.tuning.enabled {
+            let (cos, sin) = tuning_deviation(cfg, data);
+            let (cos, sin) = (tuning_cos.consume(cos), tuning_sin.consume(sin));
+            if cos == 0.0 && sin == 0.0 {
+                0.0
+            } else {
+                f32::atan2(sin, cos) / TAU * 100.0
+            }
+        } else {
+            0.0
+        };
+
+        let notes = &cfg.notes;
         let factor = 2.0f64.powf(1.0 / 12.0);
         let mut octave_power: [f32; 12] = Default::default();
         let mut average_octave: [f32; 12] = Default::default();
+        let mut note_power = vec![[0.0; 12]; notes.octaves];
 
-        let mut before = A2 / factor;
-        let mut f = A2;
-        let mut after = A2 * factor;
-
-        const OCTAVES: usize = 5;
-        let octaves = OCTAVES as f32;
+        let lowest = notes.lowest_hz() * 2.0f64.powf(tuning as f64 / 1200.0);
+        let mut before = lowest / factor;
+        let mut f = lowest;
+        let mut after = lowest * factor;
 
-        for i in 0..12 * OCTAVES {
+        for i in 0..12 * notes.octaves {
             let start = cfg.hz_to_idx((before + f) as f32 / 2.0);
             let end = cfg.hz_to_idx((after + f) as f32 / 2.0);
             // dbg!(start, end);
 
-            let p = AudibleSpec(
-                data.harmonic
-                    .iter()
-                    .skip(start)
-                    .take(end - start)
-                    .cloned()
-                    .collect(),
-            )
-            .power(cfg);
-            octave_power[i % 12] += p;
-            average_octave[i % 12] += p * (1.0 / (octaves * 2.0) + (i / 12) as f32 / octaves);
+            let p = if cfg.cqt.enabled {
+                cqt.semitone_power(cfg, i, tuning)
+            } else {
+                AudibleSpec(
+                    data.harmonic
+                        .iter()
+                        .skip(start)
+                        .take(end - start)
+                        .cloned()
+                        .collect(),
+                )
+                .power(cfg)
+            };
+            let (octave, pitch_class) = (i / 12, notes.pitch_class(i));
+            octave_power[pitch_class] += p;
+            average_octave[pitch_class] += p * notes.octave_weight(octave);
+            note_power[octave][pitch_class] = p;
 
             before = f;
             f = after;
@@ -102,17 +155,203 @@ impl PowerData {
             average_octave[i] /= octave_power[i];
         }
 
+        let channel_power = channels
+            .fft
+            .iter()
+            .map(|fft| fft.power.power(cfg))
+            .collect();
+
         Self {
             h_power_raw,
             r_power_raw,
             dr,
             p_power_raw: prev.p_power_raw.advance(p_power_raw),
             p_filtered_power: prev.p_filtered_power.advance(p_filtered_power),
-            p_bass_power: prev.p_bass_power.advance(p_bass_power),
+            bands,
             // p_filtered,
             ratio_h_p,
             octave_power,
             average_octave,
+            note_power,
+            tuning,
+            tuning_cos,
+            tuning_sin,
+            channel_power,
+        }
+    }
+}
+
+impl PowerData {
+    pub fn band(&self, name: &str) -> Option<&DData<f32>> {
+        self.bands.iter().find(|(n, _)| n == name).map(|(_, d)| d)
+    }
+}
+
+/// Finds the peaks of the harmonic spectrum and returns the sum of their
+/// deviations from equal temperament as vectors on the unit circle (one
+/// semitone being a full turn), weighted by their power
+fn tuning_deviation(cfg: &AnalysisConfig, data: &HpsData) -> (f32, f32) {
+    let c = &cfg.tuning;
+    let db = data.harmonic.into_db();
+    let max = db.iter().map(|d| **d).fold(f32::NEG_INFINITY, f32::max);
+
+    let (mut cos, mut sin) = (0.0, 0.0);
+    for i in 1..db.len().saturating_sub(1) {
+        let (l, m, r) = (*db[i - 1], *db[i], *db[i + 1]);
+        if m <= l || m < r || m < max - c.peak_range {
+            continue;
+        }
+        // parabolic interpolation of the peak on the log spectrum
+        let offset = 0.5 * (l - r) / (l - 2.0 * m + r);
+        let hz = cfg.idx_to_hz(i + cfg.min_idx()) + offset / cfg.frame_duration();
+        if hz < c.min_frequency || hz > c.max_frequency {
+            continue;
+        }
+
+        let semitones = 12.0 * f32::log2(hz / 440.0);
+        let angle = (semitones - semitones.round()) * TAU;
+        let weight = data.harmonic[i].norm_sqr();
+        cos += angle.cos() * weight;
+        sin += angle.sin() * weight;
+    }
+    (cos, sin)
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BandSource {
+    Raw,
+    Harmonic,
+    Percussive,
+    Residual,
+    /// The percussive spectrum after median filtering, which is less noisy
+    PercussiveFiltered,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct BandConfig {
+    pub name: String,
+    pub min_frequency: f32,
+    pub max_frequency: f32,
+    pub source: BandSource,
+    /// Width in Hz of the linear fade outside of both edges
+    #[serde(default)]
+    pub taper: f32,
+}
+
+impl BandConfig {
+    fn weight(&self, hz: f32) -> f32 {
+        let outside = f32::max(self.min_frequency - hz, hz - self.max_frequency);
+        if outside <= 0.0 {
+            1.0
+        } else if outside < self.taper {
+            1.0 - outside / self.taper
+        } else {
+            0.0
+        }
+    }
+
+    /// Weighted power of the band, scaled like [`AudibleSpec::power`]
+    pub fn power(&self, cfg: &AnalysisConfig, fft: &FftData, data: &HpsData) -> f32 {
+        let start = cfg
+            .hz_to_idx(self.min_frequency - self.taper)
+            .max(cfg.min_idx());
+        let end = (cfg.hz_to_idx(self.max_frequency + self.taper) + 1).min(cfg.max_idx());
+        let power = (start..end)
+            .map(|j| {
+                let i = j - cfg.min_idx();
+                let p = match self.source {
+                    BandSource::Raw => *fft.power[i],
+                    BandSource::Harmonic => data.harmonic[i].norm_sqr(),
+                    BandSource::Percussive => data.percussive[i].norm_sqr(),
+                    BandSource::Residual => data.residual[i].norm_sqr(),
+                    BandSource::PercussiveFiltered => *data.p_filtered[i],
+                };
+                p * self.weight(cfg.idx_to_hz(j))
+            })
+            .sum::<f32>();
+        power / cfg.fft.frame_len as f32
+    }
+}
+
+/// `[[bands]]` in the config, which defaults to a single percussive "bass"
+/// band
+#[derive(Deserialize, Serialize, Clone, Deref)]
+#[serde(transparent)]
+pub struct BandsConfig(pub Vec<BandConfig>);
+
+impl Default for BandsConfig {
+    fn default() -> Self {
+        Self(vec![BandConfig {
+            name: "bass".to_string(),
+            min_frequency: 0.0,
+            max_frequency: 55.0,
+            source: BandSource::PercussiveFiltered,
+            taper: 40.0,
+        }])
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct NotesConfig {
+    /// MIDI number of the lowest note, 45 is A2
+    pub lowest_note: u8,
+    pub octaves: usize,
+    /// Register position each octave stands for in `average_octave`, from the
+    /// lowest octave up. Leave empty to spread them evenly between 0 and 1.
+    pub octave_weights: Vec<f32>,
+}
+
+impl NotesConfig {
+    pub fn lowest_hz(&self) -> f64 {
+        440.0 * 2.0f64.powf((self.lowest_note as f64 - 69.0) / 12.0)
+    }
+
+    /// Pitch class of the `i`th semitone above the lowest note, 0 is A
+    pub fn pitch_class(&self, i: usize) -> usize {
+        (self.lowest_note as usize + i + 3) % 12
+    }
+
+    pub fn octave_weight(&self, octave: usize) -> f32 {
+        match self.octave_weights.get(octave) {
+            Some(&w) => w,
+            None => (octave as f32 + 0.5) / self.octaves as f32,
+        }
+    }
+}
+
+impl Default for NotesConfig {
+    fn default() -> Self {
+        Self {
+            lowest_note: 45,
+            octaves: 5,
+            octave_weights: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct TuningConfig {
+    pub enabled: bool,
+    /// Number of hops the estimate is averaged over
+    pub window: usize,
+    /// Only peaks within this many dB of the loudest one are used
+    pub peak_range: f32,
+    /// Peaks below this are too coarsely resolved to be useful
+    pub min_frequency: f32,
+    pub max_frequency: f32,
+}
+
+impl Default for TuningConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window: 400,
+            peak_range: 30.0,
+            min_frequency: 200.0,
+            max_frequency: 4000.0,
         }
     }
 }
@@ -165,3 +404,101 @@ impl<T: Sub<T, Output = T> + Clone + Into<f64>> Into<f64> for DData<T> {
         self.val.into()
     }
 }
+
+#[test]
+fn test_detuned() {
+    use crate::Analyzer;
+
+    let mut cfg = AnalysisConfig::default();
+    cfg.loudness.normalize = false;
+    let sample_rate = cfg.fft.sample_rate;
+
+    for cents in [-30.0, 0.0, 20.0, 40.0] {
+        let tuning = 2f32.powf(cents / 1200.0);
+        // A, C#, E
+        let hz = [440.0, 554.37, 659.26].map(|hz| hz * tuning);
+        let samples = (0..sample_rate)
+            .map(|i| i as f32 / sample_rate as f32)
+            .map(|t| hz.iter().map(|hz| f32::sin(t * hz * TAU)).sum::<f32>() * 0.1)
+            .collect::<Vec<_>>();
+
+        let mut analyzer = Analyzer::new(cfg.clone(), 1, sample_rate as u32);
+        analyzer.push(&samples);
+        while analyzer.next_frame().is_some() {}
+
+        let power = &analyzer.state().power;
+        assert!(
+            (power.tuning - cents).abs() < 5.0,
+            "{} != {cents}",
+            power.tuning
+        );
+    }
+}
+
+#[test]
+fn test_note_layout() {
+    use crate::Analyzer;
+
+    let mut cfg = AnalysisConfig::default();
+    cfg.loudness.normalize = false;
+    cfg.cqt.enabled = true;
+    // C3 up
+    cfg.notes.lowest_note = 48;
+    cfg.notes.octaves = 4;
+    let sample_rate = cfg.fft.sample_rate;
+
+    // E4
+    let hz = 329.63;
+    let samples = (0..cfg.cqt.frame_len + cfg.fft.frame_len)
+        .map(|i| f32::sin(i as f32 / sample_rate as f32 * hz * TAU) * 0.25)
+        .collect::<Vec<_>>();
+    let mut analyzer = Analyzer::new(cfg.clone(), 1, sample_rate as u32);
+    analyzer.push(&samples);
+    while analyzer.next_frame().is_some() {}
+
+    let power = &analyzer.state().power;
+    assert_eq!(power.note_power.len(), 4);
+    let (max, _) = power
+        .note_power
+        .iter()
+        .flatten()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .unwrap();
+    // second octave, 7 semitones above A
+    assert_eq!((max / 12, max % 12), (1, 7));
+    assert!((power.average_octave[7] - 0.375).abs() < 0.05);
+}
+
+#[test]
+fn test_bands() {
+    use crate::Analyzer;
+
+    let mut cfg = AnalysisConfig::default();
+    cfg.loudness.normalize = false;
+    let band = |name: &str, min_frequency, max_frequency| BandConfig {
+        name: name.to_string(),
+        min_frequency,
+        max_frequency,
+        source: BandSource::Raw,
+        taper: 20.0,
+    };
+    cfg.bands = BandsConfig(vec![band("low", 50.0, 200.0), band("high", 500.0, 2000.0)]);
+    assert!(cfg.validate().is_err());
+    cfg.light.bass_band = "low".to_string();
+    assert!(cfg.validate().is_ok());
+    let sample_rate = cfg.fft.sample_rate;
+
+    for (hz, loud, quiet) in [(120.0, "low", "high"), (1000.0, "high", "low")] {
+        let samples = (0..cfg.fft.frame_len * 2)
+            .map(|i| f32::sin(i as f32 / sample_rate as f32 * hz * TAU) * 0.25)
+            .collect::<Vec<_>>();
+        let mut analyzer = Analyzer::new(cfg.clone(), 1, sample_rate as u32);
+        analyzer.push(&samples);
+        while analyzer.next_frame().is_some() {}
+
+        let power = &analyzer.state().power;
+        let (loud, quiet) = (power.band(loud).unwrap(), power.band(quiet).unwrap());
+        assert!(loud.val > quiet.val * 1000.0, "{} {}", loud.val, quiet.val);
+    }
+}
@@ -0,0 +1,167 @@
+use fields_iter::FieldsInspect;
+use serde::{Deserialize, Serialize};
+
+use crate::{cfg::AnalysisConfig, util::profile_function};
+
+use super::{AudibleSpec, fft::FftData};
+
+/// Timbral descriptors of a single hop. Every field is an `f32`, so they can
+/// be picked by name with `fields_iter`.
+#[derive(Clone, Copy, Debug, Default, FieldsInspect)]
+pub struct Descriptors {
+    /// Center of mass of the power spectrum in Hz, how "bright" it sounds
+    pub centroid: f32,
+    /// Spread of the power spectrum around the centroid in Hz
+    pub bandwidth: f32,
+    /// Frequency in Hz below which `SpectralConfig::rolloff` of the power is
+    pub rolloff: f32,
+    /// Geometric over arithmetic mean of the power spectrum, from 0 for a
+    /// pure tone to 1 for white noise
+    pub flatness: f32,
+    /// Mean positive change of the magnitude spectrum since the last hop
+    pub flux: f32,
+    /// Fraction of neighbouring samples in the frame that change sign
+    pub zero_crossing_rate: f32,
+}
+
+#[derive(Clone)]
+pub struct SpectralData {
+    pub descriptors: Descriptors,
+    prev: AudibleSpec<f32>,
+}
+
+impl SpectralData {
+    pub fn blank(cfg: &AnalysisConfig) -> Self {
+        Self {
+            descriptors: Descriptors::default(),
+            prev: AudibleSpec::blank_default(cfg),
+        }
+    }
+
+    /// `frame` are the downmixed samples `fft` was computed from
+    pub fn advance(
+        mut self,
+        cfg: &AnalysisConfig,
+        fft: &FftData,
+        frame: impl ExactSizeIterator<Item = f32>,
+    ) -> Self {
+        profile_function!();
+        let d = &mut self.descriptors;
+        let hz = |i: usize| cfg.idx_to_hz(i + cfg.min_idx());
+
+        let mut flux = 0.0;
+        self.prev.mutate(|i, prev| {
+            let mag = fft.power[i].sqrt();
+            flux += f32::max(0.0, mag - *prev);
+            *prev = mag;
+        });
+        d.flux = flux / self.prev.len() as f32;
+
+        let len = frame.len();
+        let mut last = None;
+        let crossings = frame
+            .filter(|&s| {
+                let crossed = last.is_some_and(|l: f32| (s < 0.0) != (l < 0.0));
+                last = Some(s);
+                crossed
+            })
+            .count();
+        d.zero_crossing_rate = crossings as f32 / (len - 1) as f32;
+
+        let total = fft.power.iter().map(|p| **p).sum::<f32>();
+        if total <= 0.0 {
+            d.centroid = 0.0;
+            d.bandwidth = 0.0;
+            d.rolloff = 0.0;
+            d.flatness = 0.0;
+            return self;
+        }
+
+        d.centroid = fft
+            .power
+            .iter()
+            .enumerate()
+            .map(|(i, p)| hz(i) * **p)
+            .sum::<f32>()
+            / total;
+        let variance = fft
+            .power
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (hz(i) - d.centroid).powi(2) * **p)
+            .sum::<f32>()
+            / total;
+        d.bandwidth = variance.sqrt();
+
+        let mut sum = 0.0;
+        let rolloff = fft
+            .power
+            .iter()
+            .position(|p| {
+                sum += **p;
+                sum >= total * cfg.spectral.rolloff
+            })
+            .unwrap_or(fft.power.len() - 1);
+        d.rolloff = hz(rolloff);
+
+        let n = fft.power.len() as f32;
+        let log_mean = fft
+            .power
+            .iter()
+            .map(|p| f32::ln(**p + f32::MIN_POSITIVE))
+            .sum::<f32>()
+            / n;
+        d.flatness = (log_mean.exp() / (total / n)).clamp(0.0, 1.0);
+        self
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct SpectralConfig {
+    /// Fraction of the power that lies below the rolloff frequency
+    pub rolloff: f32,
+}
+
+impl Default for SpectralConfig {
+    fn default() -> Self {
+        Self { rolloff: 0.85 }
+    }
+}
+
+#[test]
+fn test_tone_and_noise() {
+    use crate::Analyzer;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+    use std::f32::consts::TAU;
+
+    let mut cfg = AnalysisConfig::default();
+    cfg.loudness.normalize = false;
+    let sample_rate = cfg.fft.sample_rate;
+    let len = cfg.fft.frame_len * 2;
+    let analyze = |samples: Vec<f32>| {
+        let mut analyzer = Analyzer::new(cfg.clone(), 1, sample_rate as u32);
+        analyzer.push(&samples);
+        while analyzer.next_frame().is_some() {}
+        analyzer.state().spectral.descriptors
+    };
+
+    let f = 1000.0;
+    let tone = analyze(
+        (0..len)
+            .map(|i| f32::sin(i as f32 / sample_rate as f32 * f * TAU) * 0.25)
+            .collect(),
+    );
+    assert!((tone.centroid - f).abs() < 50.0, "{tone:?}");
+    assert!((tone.rolloff - f).abs() < 50.0, "{tone:?}");
+    assert!(tone.bandwidth < 200.0, "{tone:?}");
+    assert!(tone.flatness < 0.01, "{tone:?}");
+    let zcr = 2.0 * f / sample_rate as f32;
+    assert!((tone.zero_crossing_rate - zcr).abs() < 0.002, "{tone:?}");
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let noise = analyze((0..len).map(|_| rng.random_range(-0.25..0.25)).collect());
+    assert!(noise.flatness > 0.3, "{noise:?}");
+    assert!(noise.centroid > 3000.0, "{noise:?}");
+    assert!(noise.zero_crossing_rate > 0.3, "{noise:?}");
+}
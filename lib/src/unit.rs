@@ -3,6 +3,24 @@ use std::ops::Mul;
 use derive_more::derive::Deref;
 use rustfft::num_complex::Complex;
 
+/// An audio sample that can be fed to the analysis, which works on `f32`
+/// samples where full scale is `1.0`
+pub trait Sample: Copy {
+    fn to_f32(self) -> f32;
+}
+
+impl Sample for f32 {
+    fn to_f32(self) -> f32 {
+        self
+    }
+}
+
+impl Sample for i16 {
+    fn to_f32(self) -> f32 {
+        self as f32 / i16::MAX as f32
+    }
+}
+
 #[derive(Clone, Copy, Debug, Deref, Default, PartialEq, PartialOrd)]
 pub struct Db(pub f32);
 
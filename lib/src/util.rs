@@ -73,4 +73,10 @@ impl RollingAverage {
     pub fn average(&self) -> f32 {
         self.sum / self.buf.len() as f32
     }
+
+    /// Replace every element, so the average is `elem`
+    pub fn fill(&mut self, elem: f32) {
+        self.buf.deq.iter_mut().for_each(|e| *e = elem);
+        self.sum = elem * self.buf.len() as f32;
+    }
 }